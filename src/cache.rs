@@ -0,0 +1,102 @@
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use crate::config::Project;
+
+/// A value which is remembered between runs in a [`Cache`].
+pub trait Entry: Serialize + DeserializeOwned {
+    /// The name of the cache file in the `cargo-embed` directory of the target directory.
+    const FILE_NAME: &'static str;
+}
+
+/// Values measured in earlier runs, stored by key in a TOML file in the target directory.
+#[derive(Debug)]
+pub struct Cache<T> {
+    path: PathBuf,
+    entries: BTreeMap<String, T>,
+}
+
+impl<T: Entry> Cache<T> {
+    /// Loads the cache from the target directory of the project, if there is one.
+    ///
+    /// A cache which cannot be read is treated as empty, as it only saves time.
+    pub fn load(project: &Project) -> Option<Self> {
+        let path = project
+            .target_dir
+            .as_ref()?
+            .join("cargo-embed")
+            .join(T::FILE_NAME);
+        Some(Self::load_from(path))
+    }
+
+    pub fn load_from(path: PathBuf) -> Self {
+        let entries = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| toml::from_str(&contents).ok())
+            .unwrap_or_default();
+        Self { path, entries }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&T> {
+        self.entries.get(key)
+    }
+
+    pub fn insert(&mut self, key: &str, value: T) {
+        self.entries.insert(key.to_string(), value);
+    }
+
+    /// Writes the cache.
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        std::fs::write(&self.path, toml::to_string(&self.entries)?)
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Cache, Entry};
+    use crate::test_util::TempDir;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize)]
+    struct Speed {
+        khz: u32,
+    }
+
+    impl Entry for Speed {
+        const FILE_NAME: &'static str = "speeds.toml";
+    }
+
+    #[test]
+    fn saves_and_loads_entries() {
+        let dir = TempDir::new("saves-and-loads-entries");
+        let path = dir.path().join("cargo-embed").join(Speed::FILE_NAME);
+
+        let mut cache = Cache::<Speed>::load_from(path.clone());
+        cache.insert("nRF52840_xxAA", Speed { khz: 8000 });
+        cache.save().unwrap();
+
+        let cache = Cache::<Speed>::load_from(path);
+        assert_eq!(cache.get("nRF52840_xxAA"), Some(&Speed { khz: 8000 }));
+        assert_eq!(cache.get("nRF52832_xxAA"), None);
+    }
+
+    #[test]
+    fn treats_unreadable_caches_as_empty() {
+        let dir = TempDir::new("treats-unreadable-caches-as-empty");
+        let cache = Cache::<Speed>::load_from(dir.write(Speed::FILE_NAME, "not toml ["));
+        assert_eq!(cache.get("nRF52840_xxAA"), None);
+    }
+}
@@ -0,0 +1,204 @@
+use super::ResolvedConfig;
+use anyhow::{anyhow, Context};
+use serde_json::value::Value;
+use std::path::PathBuf;
+
+/// The values of the variables which can be used as `${name}` in string config values.
+///
+/// `${env:NAME}` expands to the environment variable `NAME`.
+#[derive(Debug, Default)]
+pub struct Variables {
+    /// `${workspace_root}`, the root directory of the cargo workspace.
+    pub workspace_root: Option<PathBuf>,
+    /// `${package_dir}`, the directory of the selected package.
+    pub package_dir: Option<PathBuf>,
+    /// `${profile}`, the name of the selected config.
+    pub profile: String,
+    /// `${chip}`, the chip given with `--chip`. Defaults to `general.chip`.
+    pub chip: Option<String>,
+    /// `${binary}`, the name of the binary which is flashed.
+    pub binary: Option<String>,
+}
+
+impl ResolvedConfig {
+    /// Expands all variables in the string values of the config.
+    ///
+    /// `general.chip` is expanded first, so `${chip}` can be used in all other values.
+    /// Shell commands are left as they are, see [`is_command`].
+    /// A literal `${` can be written as `$${`.
+    /// Variables which are unknown or have no value are reported as an error.
+    pub fn interpolate(&mut self, variables: &Variables) -> anyhow::Result<()> {
+        let mut config = serde_json::to_value(&self.config)?;
+
+        let chip = &mut config["general"]["chip"];
+        if let Value::String(value) = chip {
+            *value = expand(value, "general.chip", |name| match name {
+                "chip" => Err("it cannot be used in 'general.chip' itself".to_string()),
+                name => variables.get(name, None),
+            })?;
+        }
+        let chip = variables
+            .chip
+            .clone()
+            .or_else(|| chip.as_str().map(str::to_string));
+
+        for (section, values) in config.as_object_mut().into_iter().flatten() {
+            for (key, value) in values.as_object_mut().into_iter().flatten() {
+                if section == "general" && key == "chip" {
+                    continue;
+                }
+                expand_all(value, &format!("{}.{}", section, key), &|name| {
+                    variables.get(name, chip.as_deref())
+                })?;
+            }
+        }
+
+        self.config = serde_json::from_value(config).context("Failed to apply the variables")?;
+        Ok(())
+    }
+}
+
+impl Variables {
+    /// Returns the value of the variable `name`, or why it has none.
+    fn get(&self, name: &str, chip: Option<&str>) -> Result<String, String> {
+        if let Some(variable) = name.strip_prefix("env:") {
+            return std::env::var(variable)
+                .map_err(|_| format!("the environment variable {} is not set", variable));
+        }
+
+        match name {
+            "workspace_root" => self
+                .workspace_root
+                .as_ref()
+                .map(|path| path.display().to_string())
+                .ok_or_else(|| "cargo-embed is not run in a cargo workspace".to_string()),
+            "package_dir" => self
+                .package_dir
+                .as_ref()
+                .map(|path| path.display().to_string())
+                .ok_or_else(|| "no package is selected, use '--package'".to_string()),
+            "profile" => Ok(self.profile.clone()),
+            "chip" => chip
+                .map(str::to_string)
+                .ok_or_else(|| "no chip is set, use 'general.chip' or '--chip'".to_string()),
+            "binary" => self
+                .binary
+                .clone()
+                .ok_or_else(|| "the binary is not known, use '--bin' or '--example'".to_string()),
+            _ => Err("there is no such variable".to_string()),
+        }
+    }
+}
+
+/// Expands the variables in all strings of `value`, which is found at `key` in the config.
+fn expand_all(
+    value: &mut Value,
+    key: &str,
+    lookup: &dyn Fn(&str) -> Result<String, String>,
+) -> anyhow::Result<()> {
+    if is_command(key) {
+        return Ok(());
+    }
+
+    match value {
+        Value::String(string) => *string = expand(string, key, lookup)?,
+        Value::Array(values) => {
+            for (i, value) in values.iter_mut().enumerate() {
+                expand_all(value, &format!("{}[{}]", key, i), lookup)?;
+            }
+        }
+        Value::Object(values) => {
+            for (name, value) in values.iter_mut() {
+                expand_all(value, &format!("{}.{}", key, name), lookup)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Returns whether the value at `key` holds shell commands, i.e. the hooks and the
+/// `command` of a patched symbol.
+///
+/// They get their context in `EMBED_*` environment variables, so `${EMBED_CHIP}`
+/// is expanded by the shell and not by cargo-embed.
+fn is_command(key: &str) -> bool {
+    key == "general.hooks" || (key.starts_with("flashing.patch[") && key.ends_with("].command"))
+}
+
+/// Replaces every `${name}` in `input` with its value and every `$${` with `${`.
+fn expand(
+    input: &str,
+    key: &str,
+    lookup: impl Fn(&str) -> Result<String, String>,
+) -> anyhow::Result<String> {
+    let mut output = String::new();
+    let mut rest = input;
+
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            output += &rest[..start - 1];
+            output += "${";
+            rest = &rest[start + 2..];
+            continue;
+        }
+
+        output += &rest[..start];
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| anyhow!("Missing '}}' after '${{' in '{}': {}", key, input))?;
+
+        let name = &rest[start + 2..end];
+        output += &lookup(name)
+            .map_err(|reason| anyhow!("Cannot expand '${{{}}}' in '{}': {}", name, key, reason))?;
+        rest = &rest[end + 1..];
+    }
+
+    output += rest;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{expand, Variables};
+
+    #[test]
+    fn expands_variables() {
+        let variables = Variables {
+            package_dir: Some("/project/fw".into()),
+            profile: "rtt".to_string(),
+            binary: Some("blinky".to_string()),
+            ..Variables::default()
+        };
+        let lookup = |name: &str| variables.get(name, Some("nRF52840_xxAA"));
+
+        assert_eq!(
+            expand(
+                "${package_dir}/logs/${binary}_${chip}",
+                "rtt.log_path",
+                lookup
+            )
+            .unwrap(),
+            "/project/fw/logs/blinky_nRF52840_xxAA"
+        );
+        assert_eq!(
+            expand("$${profile} is ${profile}", "key", lookup).unwrap(),
+            "${profile} is rtt"
+        );
+        assert_eq!(
+            expand(
+                "${workspace_root}/out.svg",
+                "flashing.flash_layout_output_path",
+                lookup
+            )
+            .unwrap_err()
+            .to_string(),
+            "Cannot expand '${workspace_root}' in 'flashing.flash_layout_output_path': \
+             cargo-embed is not run in a cargo workspace"
+        );
+        assert!(expand("${unknown}", "key", lookup).is_err());
+        assert!(expand("${env:CARGO_EMBED_SURELY_UNSET}", "key", lookup).is_err());
+        assert!(expand("${profile", "key", lookup).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use super::{Config, Configs, Override};
+
+impl Configs {
+    /// Renders an overview of all known configs for `--list-configs`.
+    ///
+    /// Every config is listed with the files which define it, the configs it derives from
+    /// in merge order and a short summary of its resolved values.
+    /// Configs which cannot be resolved are listed together with the error.
+    pub fn to_list(&self, overrides: &[Override]) -> String {
+        let mut output = String::new();
+
+        for name in self.names() {
+            let files = self.files.get(&name).cloned().unwrap_or_default();
+            output += &format!("{} ({})\n", name, files.join(", "));
+
+            match self.resolve(&name, overrides) {
+                Ok(resolved) => {
+                    // The last entry of the chain is the config itself.
+                    let parents = &resolved.chain[..resolved.chain.len() - 1];
+                    let derives = if parents.is_empty() {
+                        "nothing".to_string()
+                    } else {
+                        parents.join(" -> ")
+                    };
+                    output += &format!("    derives  {}\n", derives);
+                    for (label, value) in summary(&resolved.config) {
+                        output += &format!("    {:8} {}\n", label, value);
+                    }
+                }
+                Err(error) => output += &format!("    error    {:#}\n", error),
+            }
+        }
+
+        output
+    }
+}
+
+/// Returns the most important values of a config as label and value.
+fn summary(config: &Config) -> Vec<(&'static str, String)> {
+    let probe = &config.probe;
+    let mut probe_summary = match (&probe.alias, &probe.usb_vid, &probe.usb_pid) {
+        (Some(alias), _, _) => alias.clone(),
+        (None, Some(vid), Some(pid)) => format!("{}:{}", vid, pid),
+        _ => "any".to_string(),
+    };
+    if let (None, Some(serial)) = (&probe.alias, &probe.serial) {
+        probe_summary += &format!(":{}", serial);
+    }
+    probe_summary += &format!(", {:?}", probe.protocol);
+    if let Some(speed) = probe.speed {
+        probe_summary += &format!(", {}", speed);
+    }
+
+    let enabled = [
+        ("flashing", config.flashing.enabled),
+        ("reset", config.reset.enabled),
+        ("rtt", config.rtt.enabled),
+        ("gdb", config.gdb.enabled),
+    ]
+    .iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(name, _)| *name)
+    .collect::<Vec<_>>();
+
+    vec![
+        (
+            "chip",
+            config
+                .general
+                .chip
+                .clone()
+                .unwrap_or_else(|| "auto-detected".to_string()),
+        ),
+        ("probe", probe_summary),
+        (
+            "enabled",
+            if enabled.is_empty() {
+                "nothing".to_string()
+            } else {
+                enabled.join(", ")
+            },
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{Loader, BUILTIN_SOURCE};
+
+    #[test]
+    fn lists_configs() {
+        let mut loader = Loader::new();
+        loader
+            .merge(
+                BUILTIN_SOURCE,
+                config::File::from_str(include_str!("default.toml"), config::FileFormat::Toml),
+            )
+            .unwrap();
+        loader
+            .merge(
+                "Embed.toml",
+                config::File::from_str(
+                    r#"
+                    [default.general]
+                    chip = "nRF52840_xxAA"
+
+                    [rtt.rtt]
+                    enabled = true
+
+                    [broken.general]
+                    derives = "missing"
+                    "#,
+                    config::FileFormat::Toml,
+                ),
+            )
+            .unwrap();
+
+        let list = loader.finish().unwrap().to_list(&[]);
+        let lines = list.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines[0], "broken (Embed.toml)");
+        assert!(lines[1].starts_with("    error    The config \"broken\" derives from \"missing\""));
+        assert_eq!(lines[2], "default (built-in default, Embed.toml)");
+        assert_eq!(lines[3], "    derives  nothing");
+        assert_eq!(lines[4], "    chip     nRF52840_xxAA");
+        assert_eq!(lines[5], "    probe    any, Swd");
+        assert_eq!(lines[6], "    enabled  flashing, reset");
+        assert_eq!(lines[7], "rtt (Embed.toml)");
+        assert_eq!(lines[8], "    derives  default");
+        assert_eq!(lines[11], "    enabled  flashing, reset, rtt");
+    }
+}
@@ -0,0 +1,198 @@
+use super::{display_path, Project};
+use anyhow::Context;
+use std::path::PathBuf;
+use toml_edit::{Document, TableLike};
+
+/// Keys which moved to another section, as `(old section, new section, key)`.
+const MOVED_KEYS: &[(&str, &str, &str)] = &[("flashing", "reset", "halt_afterwards")];
+
+/// The config file names which are migrated, without their extension.
+///
+/// Only TOML files are migrated, as only they can be rewritten without losing comments.
+const FILE_NAMES: &[&str] = &[".embed", "Embed", ".embed.local", "Embed.local"];
+
+/// A config file with all deprecated keys moved to their new place.
+#[derive(Debug)]
+pub struct Migration {
+    pub path: PathBuf,
+    /// The file contents before the migration.
+    pub original: String,
+    /// The file contents after the migration, with all comments and formatting kept.
+    pub migrated: String,
+    /// A description of every moved key.
+    pub changes: Vec<String>,
+}
+
+impl Migration {
+    /// Returns a unified diff of the migration.
+    pub fn diff(&self) -> String {
+        let path = display_path(&self.path);
+        similar::TextDiff::from_lines(&self.original, &self.migrated)
+            .unified_diff()
+            .header(&path, &path)
+            .to_string()
+    }
+
+    /// Writes the migrated file.
+    pub fn write(&self) -> anyhow::Result<()> {
+        std::fs::write(&self.path, &self.migrated)
+            .with_context(|| format!("Failed to write {}", display_path(&self.path)))
+    }
+}
+
+/// Returns the migrations of all TOML config files of the project which contain deprecated keys.
+///
+/// This includes the `[package.metadata.embed]` table of the package's `Cargo.toml`.
+pub fn migrations(project: &Project) -> anyhow::Result<Vec<Migration>> {
+    let mut files = Vec::new();
+    if let Some(manifest_path) = &project.manifest_path {
+        files.push((manifest_path.clone(), &["package", "metadata", "embed"][..]));
+    }
+    for dir in project.config_dirs() {
+        for name in FILE_NAMES {
+            let path = dir.join(format!("{}.toml", name));
+            if path.is_file() {
+                files.push((path, &[][..]));
+            }
+        }
+    }
+
+    let mut migrations = Vec::new();
+    for (path, root) in files {
+        let original = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", display_path(&path)))?;
+        let mut document = original
+            .parse::<Document>()
+            .with_context(|| format!("Failed to parse {}", display_path(&path)))?;
+
+        let changes = migrate(&mut document, root);
+        if !changes.is_empty() {
+            migrations.push(Migration {
+                path,
+                original,
+                migrated: document.to_string(),
+                changes,
+            });
+        }
+    }
+
+    Ok(migrations)
+}
+
+/// Moves all deprecated keys of the configs in the table at `root` and returns what was moved.
+fn migrate(document: &mut Document, root: &[&str]) -> Vec<String> {
+    let mut configs = document.as_item_mut();
+    for key in root {
+        configs = match configs.get_mut(*key) {
+            Some(item) => item,
+            None => return Vec::new(),
+        };
+    }
+    let configs = match configs.as_table_like_mut() {
+        Some(configs) => configs,
+        None => return Vec::new(),
+    };
+
+    let mut changes = Vec::new();
+    for (name, config) in configs.iter_mut() {
+        if let Some(config) = config.as_table_like_mut() {
+            for (from, to, key) in MOVED_KEYS {
+                if let Some(change) = move_key(config, from, to, key) {
+                    changes.push(format!("{}: {}", name, change));
+                }
+            }
+        }
+    }
+    changes
+}
+
+/// Moves `from.key` to `to.key` within one config, keeping the comments above the key.
+///
+/// If the new key is set as well, the old one is dropped unless it is `true`,
+/// as the old key used to take precedence when it was enabled.
+fn move_key(config: &mut dyn TableLike, from: &str, to: &str, key: &str) -> Option<String> {
+    let section = config.get_mut(from)?.as_table_like_mut()?;
+    let decor = section.key_decor(key).cloned();
+    let value = section.remove(key)?;
+
+    let old_enabled = value.as_bool() == Some(true);
+
+    let target = config
+        .entry(to)
+        .or_insert_with(toml_edit::table)
+        .as_table_like_mut()?;
+
+    if let Some(existing) = target.get_mut(key) {
+        if old_enabled && existing.as_bool() != Some(true) {
+            *existing = value;
+            return Some(format!(
+                "replaced '{to}.{key}' with the value of '{from}.{key}', which took precedence",
+                from = from,
+                to = to,
+                key = key
+            ));
+        }
+        return Some(format!(
+            "removed '{from}.{key}', as '{to}.{key}' is set already",
+            from = from,
+            to = to,
+            key = key
+        ));
+    }
+
+    target.insert(key, value);
+    if let (Some(decor), Some(target_decor)) = (decor, target.key_decor_mut(key)) {
+        *target_decor = decor;
+    }
+    Some(format!("moved '{}.{}' to '{}.{}'", from, key, to, key))
+}
+
+#[cfg(test)]
+mod test {
+    use super::migrate;
+    use toml_edit::Document;
+
+    #[test]
+    fn moves_deprecated_keys() {
+        let mut document = r#"
+[default.flashing]
+enabled = true
+# Halt so the debugger can attach.
+halt_afterwards = true # keep
+
+[default.reset]
+enabled = true
+
+[other.flashing]
+halt_afterwards = false
+"#
+        .parse::<Document>()
+        .unwrap();
+
+        let changes = migrate(&mut document, &[]);
+        assert_eq!(
+            changes,
+            [
+                "default: moved 'flashing.halt_afterwards' to 'reset.halt_afterwards'",
+                "other: moved 'flashing.halt_afterwards' to 'reset.halt_afterwards'",
+            ]
+        );
+        assert_eq!(
+            document.to_string(),
+            r#"
+[default.flashing]
+enabled = true
+
+[default.reset]
+enabled = true
+# Halt so the debugger can attach.
+halt_afterwards = true # keep
+
+[other.flashing]
+
+[other.reset]
+halt_afterwards = false
+"#
+        );
+    }
+}
@@ -1,17 +1,75 @@
-use std::collections::HashMap;
+mod interpolate;
+mod list;
+mod migrate;
+mod overrides;
+mod project;
+mod schema;
+mod show;
+mod validate;
 
-use crate::rttui::channel::ChannelConfig;
-use anyhow::{bail, Context};
+pub use interpolate::Variables;
+pub use migrate::migrations;
+pub use overrides::Override;
+pub use project::Project;
+pub use schema::schema;
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{image::ImageFormat, rttui::channel::ChannelConfig};
+use anyhow::{anyhow, bail, Context};
 use probe_rs::WireProtocol;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+/// The top-level table which holds the probe aliases instead of a config.
+pub const PROBE_ALIASES: &str = "probes";
 
-/// A struct which holds all configs.
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Configs(HashMap<String, Config>);
+/// The name under which values from the built-in `default.toml` are reported.
+pub const BUILTIN_SOURCE: &str = "built-in default";
+
+/// A struct which holds all configs, before any derivation is applied.
+#[derive(Debug)]
+pub struct Configs {
+    configs: HashMap<String, serde_json::value::Value>,
+    /// Maps every key of every config (e.g. `default.probe.speed`) to the file which set it last.
+    sources: HashMap<String, String>,
+    /// Maps every config to the files which define it, in merge order.
+    files: HashMap<String, Vec<String>>,
+    /// The probe aliases defined in the `[probes]` table.
+    pub probes: BTreeMap<String, ProbeAlias>,
+    /// Problems with the config files which did not prevent loading them.
+    pub warnings: Vec<String>,
+}
+
+/// A config with all its parents merged in.
+#[derive(Debug)]
+pub struct ResolvedConfig {
+    /// The name of the requested config.
+    pub name: String,
+    pub config: Config,
+    /// The names of all merged configs in the order they were merged.
+    /// The last entry is the requested config itself.
+    pub chain: Vec<String>,
+    /// Maps every key (e.g. `probe.speed`) to the place which set it last.
+    pub origins: BTreeMap<String, Origin>,
+    /// The probe aliases defined in the `[probes]` table.
+    pub probes: BTreeMap<String, ProbeAlias>,
+    /// Problems with the config files which did not prevent loading them.
+    pub warnings: Vec<String>,
+}
+
+/// The place a config value was set.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Origin {
+    /// The name of the config which set the value.
+    pub config: String,
+    /// The file which set the value, or [`BUILTIN_SOURCE`].
+    pub source: String,
+}
 
 /// The main struct holding all the possible config options.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub struct Config {
     pub general: General,
     pub flashing: Flashing,
@@ -22,50 +80,266 @@ pub struct Config {
 }
 
 /// The probe config struct holding all the possible probe options.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub struct Probe {
+    /// USB vendor ID of the probe, in hex.
     pub usb_vid: Option<String>,
+    /// USB product ID of the probe, in hex.
     pub usb_pid: Option<String>,
+    /// Serial number of the probe.
     pub serial: Option<String>,
+    /// The name of a probe in the `[probes]` table. Takes precedence over `usb_vid` and `usb_pid`.
+    pub alias: Option<String>,
+    /// The protocol to be used for communicating with the target.
+    #[schemars(schema_with = "schema::wire_protocol")]
     pub protocol: WireProtocol,
-    pub speed: Option<u32>,
+    /// The speed in kHz of the data link to the target,
+    /// or "auto" to use the fastest speed which works reliably.
+    #[schemars(schema_with = "schema::speed")]
+    pub speed: Option<Speed>,
+    /// Whether the speed found with `speed = "auto"` is cached per probe serial number.
+    pub cache_speed: bool,
+    /// The duration in ms for which to wait for the probe to be connected and the target
+    /// to be attachable. 0 disables waiting.
+    pub wait_timeout: usize,
+}
+
+/// The speed of the data link to the target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Speed {
+    /// A fixed speed in kHz.
+    Khz(u32),
+    /// The fastest speed which passes memory round-trips on the target, see `cargo embed probe-speed`.
+    Auto,
+}
+
+impl Serialize for Speed {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Speed::Khz(khz) => serializer.serialize_u32(*khz),
+            Speed::Auto => serializer.serialize_str("auto"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Speed {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use std::convert::TryFrom;
+
+        struct SpeedVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for SpeedVisitor {
+            type Value = Speed;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a speed in kHz or \"auto\"")
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, value: u64) -> Result<Speed, E> {
+                u32::try_from(value)
+                    .map(Speed::Khz)
+                    .map_err(|_| E::invalid_value(serde::de::Unexpected::Unsigned(value), &self))
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, value: i64) -> Result<Speed, E> {
+                u32::try_from(value)
+                    .map(Speed::Khz)
+                    .map_err(|_| E::invalid_value(serde::de::Unexpected::Signed(value), &self))
+            }
+
+            fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<Speed, E> {
+                if value.eq_ignore_ascii_case("auto") {
+                    return Ok(Speed::Auto);
+                }
+                value
+                    .parse()
+                    .map(Speed::Khz)
+                    .map_err(|_| E::invalid_value(serde::de::Unexpected::Str(value), &self))
+            }
+        }
+
+        deserializer.deserialize_any(SpeedVisitor)
+    }
+}
+
+impl std::fmt::Display for Speed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Speed::Khz(khz) => write!(f, "{} kHz", khz),
+            Speed::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+/// A probe with a name, defined in the `[probes]` table.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema)]
+pub struct ProbeAlias {
+    /// USB vendor ID of the probe, in hex.
+    pub usb_vid: String,
+    /// USB product ID of the probe, in hex.
+    pub usb_pid: String,
+    /// Serial number of the probe.
+    pub serial: Option<String>,
 }
 
 /// The flashing config struct holding all the possible flashing options.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub struct Flashing {
+    /// Whether or not the target should be flashed.
     pub enabled: bool,
+    /// Whether or not the target should be halted after reset.
+    /// Moved to the reset section.
     #[deprecated(
         since = "0.9.0",
         note = "The 'halt_afterwards' key has moved to the 'reset' section"
     )]
     pub halt_afterwards: bool,
+    /// Whether or not bytes erased but not rewritten with data from the ELF
+    /// should be restored with their contents before erasing.
     pub restore_unwritten_bytes: bool,
+    /// The path where the assembled flash layout should be written to.
     pub flash_layout_output_path: Option<String>,
+    /// The format the flash layout is written in.
+    pub flash_layout_output_format: LayoutFormat,
+    /// Whether the flash is read back after programming and compared with the images.
+    pub verify: bool,
+    /// Whether flashing is skipped if the target already contains the same images.
+    pub skip_if_unchanged: bool,
+    /// Images which are flashed together with the firmware, e.g. a bootloader or calibration data.
+    pub images: Vec<ImageConfig>,
+    /// Symbols which are overwritten with a value for every unit, e.g. a serial number.
+    pub patch: Vec<PatchConfig>,
+    /// The file a line is appended to for every patched unit.
+    pub patch_log: Option<String>,
+}
+
+/// The format of the flash layout written to `flashing.flash_layout_output_path`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LayoutFormat {
+    /// An image of the sectors, pages and fills.
+    Svg,
+    /// The addresses and sizes of the sectors, pages and fills.
+    Json,
+    /// A table of the sectors, pages and fills with a bar map.
+    Text,
+}
+
+/// An image in `flashing.images`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema)]
+pub struct ImageConfig {
+    /// The path to the image, relative to the current directory.
+    pub path: String,
+    /// The format of the image. Detected from the file if not given.
+    pub format: Option<ImageFormat>,
+    /// The address a raw binary image is flashed to.
+    pub offset: Option<u32>,
+}
+
+/// A symbol in `flashing.patch` and where its value comes from.
+///
+/// The value is either `value`, the number in the `counter` file, the `column` of the
+/// row of `csv` given by the `counter` file, or the output of `command`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema)]
+pub struct PatchConfig {
+    /// The name of the symbol in the ELF file.
+    pub symbol: String,
+    /// How the value is turned into the bytes of the symbol. Defaults to "le".
+    pub encoding: Option<PatchEncoding>,
+    /// A literal value.
+    pub value: Option<String>,
+    /// A file containing a number, which is incremented after every flashed unit.
+    pub counter: Option<String>,
+    /// A CSV file with a header row.
+    pub csv: Option<String>,
+    /// The column of `csv` containing the value.
+    pub column: Option<String>,
+    /// A shell command printing the value.
+    pub command: Option<String>,
+}
+
+/// How the value of a patched symbol is turned into bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum PatchEncoding {
+    /// An unsigned integer in little endian, given in decimal or in hex with `0x`.
+    Le,
+    /// An unsigned integer in big endian, given in decimal or in hex with `0x`.
+    Be,
+    /// Hex bytes, optionally separated by `:`, `-` or spaces, e.g. a MAC address.
+    Hex,
+    /// UTF-8 text, padded with zeros.
+    String,
 }
 
 /// The reset config struct holding all the possible reset options.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub struct Reset {
+    /// Whether or not the target should be reset.
     pub enabled: bool,
+    /// Whether or not the target should be halted after reset.
     pub halt_afterwards: bool,
 }
 
 /// The general config struct holding all the possible general options.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub struct General {
+    /// The chip name of the chip to be debugged.
     pub chip: Option<String>,
+    /// A list of chip descriptions to be loaded during runtime.
     pub chip_descriptions: Vec<String>,
+    /// The log level to be used.
+    #[schemars(schema_with = "schema::log_level")]
     pub log_level: log::Level,
-    pub derives: Option<String>,
+    /// The config (or list of configs) this config derives from.
+    pub derives: Option<Derives>,
     /// Use this flag to assert the nreset & ntrst pins during attaching the probe to the chip.
     pub connect_under_reset: bool,
+    /// Shell commands which are run at the steps of cargo-embed.
+    pub hooks: Hooks,
+}
+
+/// Lists of shell commands which are run one after another at the steps of cargo-embed.
+///
+/// A command which exits with a non-zero status aborts cargo-embed.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct Hooks {
+    /// Run before attaching to the target, e.g. to power the board.
+    pub pre_attach: Vec<String>,
+    /// Run after flashing, also if the flash was up to date.
+    pub post_flash: Vec<String>,
+    /// Run after resetting the target.
+    pub post_reset: Vec<String>,
+    /// Run once when cargo-embed exits, also after errors, panics and Ctrl-C once the config was loaded.
+    pub on_exit: Vec<String>,
+}
+
+/// The configs a config derives from.
+///
+/// Either a single config name or a list of config names, which are merged from left to right.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Derives {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl Derives {
+    /// Returns the names of all parent configs in merge order.
+    pub fn names(&self) -> Vec<&str> {
+        match self {
+            Derives::Single(name) => vec![name.as_str()],
+            Derives::Multiple(names) => names.iter().map(String::as_str).collect(),
+        }
+    }
 }
 
 /// The rtt config struct holding all the possible rtt options.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub struct Rtt {
+    /// Whether or not an RTTUI should be opened after flashing.
     pub enabled: bool,
+    /// A list of channel associations to be displayed. If left empty, all channels are displayed.
     pub channels: Vec<ChannelConfig>,
     /// Connection timeout in ms.
     pub timeout: usize,
@@ -73,106 +347,667 @@ pub struct Rtt {
     pub show_timestamps: bool,
     /// Whether to save rtt history buffer on exit to file named history.txt
     pub log_enabled: bool,
-    /// Where to save rtt history buffer relative to manifest path.
+    /// Where to save rtt history buffer, relative to the current directory.
     pub log_path: PathBuf,
 }
 
 /// The gdb config struct holding all the possible gdb options.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub struct Gdb {
+    /// Whether or not a GDB server should be opened after flashing.
     pub enabled: bool,
+    /// The connection string in host:port format where the GDB server will open a socket.
     pub gdb_connection_string: Option<String>,
 }
 
 impl Configs {
-    pub fn try_new(name: impl AsRef<str>) -> anyhow::Result<Config> {
-        let mut s = config::Config::new();
+    pub fn try_new(
+        name: impl AsRef<str>,
+        project: &Project,
+        overrides: &[Override],
+    ) -> anyhow::Result<ResolvedConfig> {
+        Self::load(project)?.resolve(name.as_ref(), overrides)
+    }
+
+    /// Loads the built-in default config and all configs of the given project.
+    ///
+    /// Sources are merged in the following order, later ones taking precedence:
+    /// 1. The built-in default config.
+    /// 2. The `package.metadata.embed` table of the package's `Cargo.toml`.
+    /// 3. The project-specific config files in the workspace root,
+    ///    the package directory and the current directory.
+    /// 4. The local config files in the same directories.
+    pub fn load(project: &Project) -> anyhow::Result<Self> {
+        let mut loader = Loader::new();
 
         // Start off by merging in the default configuration file.
-        s.merge(config::File::from_str(
-            include_str!("default.toml"),
-            config::FileFormat::Toml,
-        ))?;
+        loader.merge(
+            BUILTIN_SOURCE,
+            config::File::from_str(include_str!("default.toml"), config::FileFormat::Toml),
+        )?;
+
+        if let (Some(metadata), Some(manifest_path)) = (&project.metadata, &project.manifest_path) {
+            let source = format!("{} [package.metadata.embed]", display_path(manifest_path));
+            loader
+                .merge(
+                    &source,
+                    config::File::from_str(&metadata.to_string(), config::FileFormat::Json),
+                )
+                .with_context(|| format!("Failed to merge config from '{}'", source))?;
+        }
+
+        let dirs = project.config_dirs();
 
         // Ordered list of config files, which are handled in the order specified here.
-        let config_files = [
+        // Each group is looked up in all directories before the next group is,
+        // so local files always take precedence over project-specific ones.
+        let config_files: &[&[&str]] = &[
             // Merge in the project-specific configuration files.
             // These files may be added to your git repo.
-            ".embed",
-            "Embed",
+            &[".embed", "Embed"],
             // Merge in the local configuration files.
             // These files should not be added to your git repo.
-            ".embed.local",
-            "Embed.local",
-            // As described in https://github.com/mehcode/config-rs/issues/101
-            // the above lines will not work unless that bug is fixed, until
-            // then, we add ".ext" to be replaced with a valid format name.
-            ".embed.local.ext",
-            "Embed.local.ext",
+            &[".embed.local", "Embed.local"],
         ];
 
-        for file in &config_files {
-            s.merge(config::File::with_name(file).required(false))
-                .with_context(|| format!("Failed to merge config file '{}", file))?;
+        for group in config_files {
+            for dir in &dirs {
+                for file in *group {
+                    let mut found = find_files(&dir.join(file)).into_iter();
+
+                    if let Some((path, format)) = found.next() {
+                        let source = display_path(&path);
+
+                        let ignored = found
+                            .map(|(path, _)| display_path(&path))
+                            .collect::<Vec<_>>();
+                        if !ignored.is_empty() {
+                            loader.warnings.push(format!(
+                                "Found {} next to {}, only {} is used",
+                                ignored.join(" and "),
+                                source,
+                                source
+                            ));
+                        }
+
+                        // Files are required from here on, so a file which cannot be
+                        // read or parsed is reported instead of being skipped.
+                        loader
+                            .merge(&source, config::File::from(path).format(format))
+                            .with_context(|| format!("Failed to merge config file '{}'", source))?;
+                    }
+                }
+            }
         }
 
-        let map: HashMap<String, serde_json::value::Value> = s.try_into()?;
+        loader.finish()
+    }
 
-        let config = match map.get(name.as_ref()) {
-            Some(c) => c,
-            None => bail!(
-                "Cannot find config \"{}\" (available configs: {})",
-                name.as_ref(),
-                map.keys().cloned().collect::<Vec<String>>().join(", "),
-            ),
-        };
+    /// Resolves the config with the given name.
+    ///
+    /// All configs it derives from are merged first, depth first and from left to right.
+    /// Every config is merged only once, even if it is reachable through several parents.
+    /// The `overrides` are applied last, in the given order.
+    pub fn resolve(&self, name: &str, overrides: &[Override]) -> anyhow::Result<ResolvedConfig> {
+        if !self.configs.contains_key(name) {
+            bail!(
+                "Cannot find config \"{}\" (available configs: {}). Use '--list-configs' for details.",
+                name,
+                self.names().join(", "),
+            );
+        }
+
+        let mut chain = Vec::new();
+        self.linearize(name, &mut Vec::new(), &mut chain)?;
 
         let mut s = config::Config::new();
+        let mut origins = BTreeMap::new();
+
+        for name in &chain {
+            let config = &self.configs[name];
 
-        Self::apply(name.as_ref(), &mut s, config, &map)?;
+            // Merge this current config.
+            s.merge(config::File::from_str(
+                // This unwrap can never fail as we just deserialized this. The reverse has to work!
+                &serde_json::to_string(config).unwrap(),
+                config::FileFormat::Json,
+            ))?;
+
+            for (key, _) in leaves(config) {
+                // Keys which were not merged by the loader, like parts of values it merged
+                // as a whole, have no recorded source.
+                let source = self
+                    .sources
+                    .get(&format!("{}.{}", name, key))
+                    .cloned()
+                    .unwrap_or_else(|| BUILTIN_SOURCE.to_string());
+                origins.insert(
+                    key,
+                    Origin {
+                        config: name.clone(),
+                        source,
+                    },
+                );
+            }
+        }
 
         // You can deserialize (and thus freeze) the entire configuration
-        Ok(s.try_into()?)
+        let mut config = s.clone().try_into()?;
+
+        for setting in overrides {
+            let value = setting.to_config();
+
+            let mut configs = HashMap::new();
+            configs.insert(name.to_string(), value.clone());
+            if let Some(unknown) = validate::unknown_keys(&configs).first() {
+                bail!(
+                    "Cannot override '{}' from {}: {}",
+                    setting.path(),
+                    setting.source,
+                    unknown
+                );
+            }
+
+            s.merge(config::File::from_str(
+                &value.to_string(),
+                config::FileFormat::Json,
+            ))?;
+
+            // Deserialize after every override, so a value of the wrong type is reported
+            // together with the place it came from.
+            config = s.clone().try_into().with_context(|| {
+                format!(
+                    "Invalid value '{}' for '{}' from {}",
+                    setting.value,
+                    setting.path(),
+                    setting.source
+                )
+            })?;
+
+            origins.insert(
+                setting.path(),
+                Origin {
+                    config: name.to_string(),
+                    source: setting.source.clone(),
+                },
+            );
+        }
+
+        Ok(ResolvedConfig {
+            name: name.to_string(),
+            config,
+            chain,
+            origins,
+            probes: self.probes.clone(),
+            warnings: self.warnings.clone(),
+        })
     }
 
-    pub fn apply(
+    /// Returns the names of all known configs in alphabetical order.
+    pub fn names(&self) -> Vec<String> {
+        let mut names = self.configs.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// Appends `name` and all the configs it derives from to `chain` in merge order.
+    ///
+    /// `stack` holds the configs which are currently being resolved and is used to detect cycles.
+    fn linearize(
+        &self,
         name: &str,
-        s: &mut config::Config,
-        config: &serde_json::value::Value,
-        map: &HashMap<String, serde_json::value::Value>,
-    ) -> Result<(), config::ConfigError> {
-        // If this config derives from another config, merge the other config first.
-        // Do this recursively.
-        if let Some(derives) = config
-            .get("general")
-            .and_then(|g| g.get("derives").and_then(|d| d.as_str()))
-            .or(Some("default"))
-        {
-            if derives == name {
-                log::warn!("Endless recursion within the {} config.", derives);
-            } else if let Some(dconfig) = map.get(derives) {
-                Self::apply(derives, s, dconfig, map)?;
+        stack: &mut Vec<String>,
+        chain: &mut Vec<String>,
+    ) -> anyhow::Result<()> {
+        if let Some(position) = stack.iter().position(|n| n == name) {
+            let mut cycle = stack[position..].to_vec();
+            cycle.push(name.to_string());
+            bail!(
+                "The config \"{}\" derives from itself: {}",
+                name,
+                cycle.join(" -> ")
+            );
+        }
+
+        if chain.iter().any(|n| n == name) {
+            return Ok(());
+        }
+
+        let config = self.configs.get(name).ok_or_else(|| {
+            anyhow!(
+                "The config \"{}\" derives from \"{}\", which does not exist (available configs: {})",
+                stack.last().map(String::as_str).unwrap_or_default(),
+                name,
+                self.names().join(", "),
+            )
+        })?;
+
+        stack.push(name.to_string());
+        for parent in Self::parents(name, config)? {
+            self.linearize(&parent, stack, chain)?;
+        }
+        stack.pop();
+
+        chain.push(name.to_string());
+
+        Ok(())
+    }
+
+    /// Returns the names of the configs the given config derives from.
+    ///
+    /// Every config except `default` itself derives from `default` if nothing else is specified.
+    fn parents(name: &str, config: &serde_json::value::Value) -> anyhow::Result<Vec<String>> {
+        match config.get("general").and_then(|g| g.get("derives")) {
+            None | Some(serde_json::value::Value::Null) if name == "default" => Ok(vec![]),
+            None | Some(serde_json::value::Value::Null) => Ok(vec!["default".to_string()]),
+            Some(derives) => {
+                let derives: Derives =
+                    serde_json::from_value(derives.clone()).with_context(|| {
+                        format!(
+                            "The 'general.derives' key of the config \"{}\" must be a config name or a list of config names",
+                            name
+                        )
+                    })?;
+                Ok(derives.names().into_iter().map(String::from).collect())
+            }
+        }
+    }
+}
+
+/// Merges config sources on top of each other, remembering which source set which key.
+struct Loader {
+    merged: config::Config,
+    sources: HashMap<String, String>,
+    files: HashMap<String, Vec<String>>,
+    warnings: Vec<String>,
+}
+
+impl Loader {
+    fn new() -> Self {
+        Self {
+            merged: config::Config::new(),
+            sources: HashMap::new(),
+            files: HashMap::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Merges `source` on top of everything merged so far and records `name` as the source of its keys.
+    fn merge<T>(&mut self, name: &str, source: T) -> anyhow::Result<()>
+    where
+        T: config::Source + Send + Sync + 'static,
+    {
+        let mut layer = config::Config::new();
+        layer.merge(source)?;
+
+        let map: HashMap<String, serde_json::value::Value> = layer.clone().try_into()?;
+
+        let unknown_keys = validate::unknown_keys(&map);
+        if !unknown_keys.is_empty() {
+            bail!(
+                "{} contains keys which are not config options:\n{}",
+                name,
+                unknown_keys
+                    .iter()
+                    .map(|key| format!("  - {}", key))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            );
+        }
+        for (config, value) in &map {
+            if config == PROBE_ALIASES {
+                continue;
+            }
+            self.files
+                .entry(config.clone())
+                .or_default()
+                .push(name.to_string());
+            for (key, _) in leaves(value) {
+                self.sources
+                    .insert(format!("{}.{}", config, key), name.to_string());
             }
         }
 
-        // Merge this current config.
-        s.merge(config::File::from_str(
-            // This unwrap can never fail as we just deserialized this. The reverse has to work!
-            &serde_json::to_string(&config).unwrap(),
-            config::FileFormat::Json,
-        ))
-        .map(|_| ())
+        self.merged.merge(layer)?;
+
+        Ok(())
+    }
+
+    fn finish(self) -> anyhow::Result<Configs> {
+        let mut configs: HashMap<String, serde_json::value::Value> = self.merged.try_into()?;
+
+        let probes = match configs.remove(PROBE_ALIASES) {
+            Some(probes) => serde_json::from_value(probes)
+                .context("The [probes] table must map names to tables with a 'usb_vid', 'usb_pid' and optional 'serial'")?,
+            None => BTreeMap::new(),
+        };
+
+        Ok(Configs {
+            configs,
+            sources: self.sources,
+            files: self.files,
+            probes,
+            warnings: self.warnings,
+        })
     }
 }
 
+/// The file extensions config files are looked up with, in order of preference.
+const FILE_FORMATS: &[(&str, config::FileFormat)] = &[
+    ("toml", config::FileFormat::Toml),
+    ("json", config::FileFormat::Json),
+    ("yaml", config::FileFormat::Yaml),
+    ("yml", config::FileFormat::Yaml),
+];
+
+/// Returns all existing config files for the given path without extension,
+/// in order of preference.
+///
+/// The extension is appended to the path, so `Embed.local` finds `Embed.local.toml`.
+pub fn find_files(path: &Path) -> Vec<(PathBuf, config::FileFormat)> {
+    FILE_FORMATS
+        .iter()
+        .map(|(extension, format)| {
+            let mut file = path.as_os_str().to_owned();
+            file.push(".");
+            file.push(extension);
+            (PathBuf::from(file), *format)
+        })
+        .filter(|(file, _)| file.is_file())
+        .collect()
+}
+
+/// Returns the path relative to the current directory if it is below it, for shorter messages.
+fn display_path(path: &Path) -> String {
+    std::env::current_dir()
+        .ok()
+        .and_then(|dir| path.strip_prefix(dir.canonicalize().unwrap_or(dir)).ok())
+        .unwrap_or(path)
+        .display()
+        .to_string()
+}
+
+/// Returns the dotted path and value of every value in the given config which is not a table.
+fn leaves(value: &serde_json::value::Value) -> Vec<(String, &serde_json::value::Value)> {
+    let mut leaves = Vec::new();
+    if let serde_json::value::Value::Object(map) = value {
+        for (key, value) in map {
+            if value.is_object() {
+                leaves.extend(
+                    self::leaves(value)
+                        .into_iter()
+                        .map(|(child, value)| (format!("{}.{}", key, child), value)),
+                );
+            } else {
+                leaves.push((key.clone(), value));
+            }
+        }
+    }
+    leaves
+}
+
 #[cfg(test)]
 mod test {
-    use super::Configs;
+    use super::{find_files, Configs, Loader, Override, Project, Speed, Variables, BUILTIN_SOURCE};
+    use crate::test_util::TempDir;
 
     #[test]
     fn default_config() {
         // Ensure the default config can be parsed.
 
-        let _config = Configs::try_new("default").unwrap();
+        let _config = Configs::try_new("default", &Project::default(), &[]).unwrap();
+    }
+
+    fn configs(toml: &str) -> Configs {
+        let mut loader = Loader::new();
+        loader
+            .merge(
+                BUILTIN_SOURCE,
+                config::File::from_str(include_str!("default.toml"), config::FileFormat::Toml),
+            )
+            .unwrap();
+        loader
+            .merge(
+                "Embed.toml",
+                config::File::from_str(toml, config::FileFormat::Toml),
+            )
+            .unwrap();
+        loader.finish().unwrap()
+    }
+
+    #[test]
+    fn derives_from_multiple_parents() {
+        let configs = configs(
+            r#"
+            [board.probe]
+            speed = 1000
+            [board.rtt]
+            timeout = 10
+            [mode.rtt]
+            enabled = true
+            timeout = 20
+            [app.general]
+            derives = ["board", "mode"]
+            "#,
+        );
+
+        let resolved = configs.resolve("app", &[]).unwrap();
+
+        assert_eq!(resolved.chain, ["default", "board", "mode", "app"]);
+        assert_eq!(resolved.config.probe.speed, Some(Speed::Khz(1000)));
+        assert!(resolved.config.rtt.enabled);
+        assert_eq!(resolved.config.rtt.timeout, 20);
+        assert_eq!(resolved.origins["probe.speed"].config, "board");
+        assert_eq!(resolved.origins["probe.speed"].source, "Embed.toml");
+        assert_eq!(resolved.origins["rtt.timeout"].config, "mode");
+        assert_eq!(resolved.origins["gdb.enabled"].config, "default");
+        assert_eq!(resolved.origins["gdb.enabled"].source, BUILTIN_SOURCE);
+    }
+
+    #[test]
+    fn keeps_variables_in_hook_commands() {
+        let configs = configs(
+            r#"
+            [app.general.hooks]
+            post_flash = ["echo ${EMBED_CHIP}"]
+            [app.flashing]
+            flash_layout_output_path = "${profile}.svg"
+            "#,
+        );
+
+        let mut resolved = configs.resolve("app", &[]).unwrap();
+        resolved
+            .interpolate(&Variables {
+                profile: "app".to_string(),
+                ..Variables::default()
+            })
+            .unwrap();
+        assert_eq!(
+            resolved.config.general.hooks.post_flash,
+            ["echo ${EMBED_CHIP}"]
+        );
+        assert_eq!(
+            resolved.config.flashing.flash_layout_output_path.as_deref(),
+            Some("app.svg")
+        );
+    }
+
+    #[test]
+    fn keeps_variables_in_patch_commands() {
+        let configs = configs(
+            r#"
+            [app.flashing]
+            patch = [
+                { symbol = "SERIAL", command = "next-serial ${EMBED_SYMBOL}" },
+                { symbol = "BOARD", value = "${profile}" },
+            ]
+            "#,
+        );
+
+        let mut resolved = configs.resolve("app", &[]).unwrap();
+        resolved
+            .interpolate(&Variables {
+                profile: "app".to_string(),
+                ..Variables::default()
+            })
+            .unwrap();
+        let patch = &resolved.config.flashing.patch;
+        assert_eq!(
+            patch[0].command.as_deref(),
+            Some("next-serial ${EMBED_SYMBOL}")
+        );
+        assert_eq!(patch[1].value.as_deref(), Some("app"));
+    }
+
+    #[test]
+    fn reads_probe_aliases() {
+        let configs = configs(
+            r#"
+            [probes.bench-3-nrf]
+            usb_vid = "1366"
+            usb_pid = "1015"
+            serial = "000683000000"
+            [app.probe]
+            alias = "bench-3-nrf"
+            "#,
+        );
+
+        assert_eq!(configs.names(), ["app", "default"]);
+        assert_eq!(configs.probes["bench-3-nrf"].usb_vid, "1366");
+
+        let resolved = configs.resolve("app", &[]).unwrap();
+        assert_eq!(resolved.config.probe.alias.as_deref(), Some("bench-3-nrf"));
+        assert_eq!(
+            resolved.probes["bench-3-nrf"].serial.as_deref(),
+            Some("000683000000")
+        );
+    }
+
+    #[test]
+    fn annotates_sources() {
+        let configs = configs(
+            r#"
+            [board.probe]
+            speed = 1000
+            [app.general]
+            derives = "board"
+            chip = "nRF52840_xxAA"
+            "#,
+        );
+
+        let annotated = configs
+            .resolve("app", &[])
+            .unwrap()
+            .to_annotated_toml()
+            .unwrap();
+        let line = |key: &str| {
+            annotated
+                .lines()
+                .find(|line| line.starts_with(key))
+                .unwrap()
+                .to_string()
+        };
+
+        assert!(line("general.chip = \"nRF52840_xxAA\"").ends_with("# Embed.toml"));
+        assert!(line("probe.speed = 1000").ends_with("# Embed.toml (config \"board\")"));
+        assert!(
+            line("probe.protocol = \"Swd\"").ends_with("# built-in default (config \"default\")")
+        );
+    }
+
+    #[test]
+    fn annotates_keys_without_source() {
+        let mut configs = configs(
+            r#"
+            [default.probe]
+            speed = 1000
+            "#,
+        );
+        configs.sources.remove("default.probe.speed");
+
+        let resolved = configs.resolve("default", &[]).unwrap();
+        let annotated = resolved.to_annotated_toml().unwrap();
+        assert!(annotated
+            .lines()
+            .any(|line| line.starts_with("probe.speed = 1000")
+                && line.ends_with("# built-in default")));
+    }
+
+    #[test]
+    fn applies_overrides() {
+        let configs = configs(
+            r#"
+            [default.probe]
+            speed = 1000
+            "#,
+        );
+        let mut speed: Override = "probe.speed=4000".parse().unwrap();
+        speed.source = "environment variable CARGO_EMBED_PROBE_SPEED".to_string();
+        let overrides = [speed, "probe.speed=8000".parse().unwrap()];
+
+        let resolved = configs.resolve("default", &overrides).unwrap();
+        assert_eq!(resolved.config.probe.speed, Some(Speed::Khz(8000)));
+        assert_eq!(resolved.origins["probe.speed"].source, "--set");
+
+        let resolved = configs
+            .resolve("default", &["probe.speed=auto".parse().unwrap()])
+            .unwrap();
+        assert_eq!(resolved.config.probe.speed, Some(Speed::Auto));
+
+        let error = configs
+            .resolve("default", &["probe.speed=fast".parse().unwrap()])
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid value 'fast' for 'probe.speed' from --set"
+        );
+
+        let error = configs
+            .resolve("default", &["probe.sped=8000".parse().unwrap()])
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Cannot override 'probe.sped' from --set: unknown key 'default.probe.sped', did you mean 'speed'?"
+        );
+    }
+
+    #[test]
+    fn finds_files_in_all_formats() {
+        let temp = TempDir::new("finds-files-in-all-formats");
+        for file in &["Embed.local.yaml", "Embed.local.toml", "Embed.json"] {
+            temp.write(file, "");
+        }
+        let dir = temp.path();
+
+        let found = find_files(&dir.join("Embed.local"))
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            [dir.join("Embed.local.toml"), dir.join("Embed.local.yaml")]
+        );
+        assert!(find_files(&dir.join(".embed.local")).is_empty());
+    }
+
+    #[test]
+    fn detects_derivation_cycles() {
+        let configs = configs(
+            r#"
+            [a.general]
+            derives = "b"
+            [b.general]
+            derives = ["default", "c"]
+            [c.general]
+            derives = "a"
+            "#,
+        );
+
+        let error = configs.resolve("a", &[]).unwrap_err().to_string();
+
+        assert_eq!(
+            error,
+            "The config \"a\" derives from itself: a -> b -> c -> a"
+        );
     }
 }
@@ -0,0 +1,189 @@
+use super::validate;
+use serde_json::value::Value;
+use std::str::FromStr;
+
+/// The prefix of environment variables which override config keys.
+const ENV_PREFIX: &str = "CARGO_EMBED_";
+
+/// A value which overrides a single key of the resolved config.
+///
+/// Overrides are applied on top of all config files and derived configs.
+/// Overrides from environment variables are applied first, followed by the ones
+/// given with `--set` on the command line, in the order they were given.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Override {
+    /// The section of the key, e.g. `rtt`.
+    pub section: String,
+    /// The dotted path of the key within its section, e.g. `enabled` or `hooks.pre_attach`.
+    pub key: String,
+    /// The value exactly as it was given.
+    pub value: String,
+    /// Where the override came from, e.g. `--set` or the name of the environment variable.
+    pub source: String,
+}
+
+impl Override {
+    /// Returns the overrides given by `CARGO_EMBED_<SECTION>_<KEY>` environment variables,
+    /// and a warning for every other variable with that prefix.
+    ///
+    /// The name of a variable is the path of the key in uppercase, with dots replaced by
+    /// underscores, e.g. `CARGO_EMBED_PROBE_USB_VID` for `probe.usb_vid` and
+    /// `CARGO_EMBED_GENERAL_HOOKS_PRE_ATTACH` for `general.hooks.pre_attach`.
+    /// Only names of known keys are accepted, so underscores within key names are not ambiguous.
+    pub fn from_env() -> (Vec<Override>, Vec<String>) {
+        let paths = validate::known_paths();
+
+        let mut overrides = Vec::new();
+        let mut warnings = Vec::new();
+        for (name, value) in std::env::vars() {
+            if !name.starts_with(ENV_PREFIX) {
+                continue;
+            }
+            match find_env_path(&name, &paths) {
+                Some(path) => {
+                    let mut parts = path.splitn(2, '.');
+                    overrides.push(Override {
+                        section: parts.next().unwrap_or_default().to_string(),
+                        key: parts.next().unwrap_or_default().to_string(),
+                        value,
+                        source: format!("environment variable {}", name),
+                    });
+                }
+                None => warnings.push(format!(
+                    "Ignoring the environment variable {}, it does not name a config key.",
+                    name
+                )),
+            }
+        }
+
+        // Make the order independent of the environment.
+        overrides.sort_by(|a, b| a.source.cmp(&b.source));
+        warnings.sort();
+        (overrides, warnings)
+    }
+
+    /// Returns the dotted path of the overridden key, e.g. `rtt.enabled`.
+    pub fn path(&self) -> String {
+        format!("{}.{}", self.section, self.key)
+    }
+
+    /// Returns the override as a config with only the overridden key in it.
+    ///
+    /// The value is interpreted as a TOML value if possible, and as a plain string otherwise,
+    /// so both `general.chip=nRF52840_xxAA` and `general.chip="nRF52840_xxAA"` work.
+    pub fn to_config(&self) -> Value {
+        let value = toml::from_str::<toml::value::Table>(&format!("value = {}", self.value))
+            .ok()
+            .and_then(|mut table| table.remove("value"))
+            .and_then(|value| serde_json::to_value(value).ok())
+            .unwrap_or_else(|| Value::String(self.value.clone()));
+
+        let value = self
+            .key
+            .rsplit('.')
+            .fold(value, |value, key| serde_json::json!({ key: value }));
+        serde_json::json!({ &self.section: value })
+    }
+}
+
+impl FromStr for Override {
+    type Err = String;
+
+    /// Parses an override given as `section.key=value`, where the key may be a dotted path
+    /// into a nested table, e.g. `general.hooks.pre_attach=["./power-on.sh"]`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '=');
+        let path = parts.next().unwrap_or_default().trim();
+        let value = parts
+            .next()
+            .ok_or_else(|| format!("'{}' is missing a value, use 'section.key=value'", s))?;
+
+        let mut path_parts = path.splitn(2, '.');
+        match (path_parts.next(), path_parts.next()) {
+            (Some(section), Some(key)) if !section.is_empty() && !key.is_empty() => Ok(Override {
+                section: section.to_string(),
+                key: key.to_string(),
+                value: value.trim().to_string(),
+                source: "--set".to_string(),
+            }),
+            _ => Err(format!(
+                "'{}' is not a valid key, use 'section.key=value'",
+                path
+            )),
+        }
+    }
+}
+
+/// Returns the path of the known key which the environment variable `name` overrides.
+fn find_env_path<'a>(name: &str, paths: &'a [String]) -> Option<&'a str> {
+    paths
+        .iter()
+        .find(|path| env_name(path) == name)
+        .map(String::as_str)
+}
+
+/// Returns the name of the environment variable which overrides the key at `path`.
+fn env_name(path: &str) -> String {
+    format!("{}{}", ENV_PREFIX, path.replace('.', "_").to_uppercase())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{env_name, find_env_path, validate, Override};
+
+    #[test]
+    fn parses_overrides() {
+        let speed: Override = "probe.speed=1000".parse().unwrap();
+        assert_eq!(speed.path(), "probe.speed");
+        assert_eq!(
+            speed.to_config(),
+            serde_json::json!({ "probe": { "speed": 1000 } })
+        );
+
+        let chip: Override = "general.chip=nRF52840_xxAA".parse().unwrap();
+        assert_eq!(
+            chip.to_config(),
+            serde_json::json!({ "general": { "chip": "nRF52840_xxAA" } })
+        );
+
+        assert!("probe.speed".parse::<Override>().is_err());
+        assert!("speed=1000".parse::<Override>().is_err());
+    }
+
+    #[test]
+    fn nests_dotted_keys() {
+        let hooks: Override = "general.hooks.pre_attach=[\"./power-on.sh\"]"
+            .parse()
+            .unwrap();
+        assert_eq!(hooks.path(), "general.hooks.pre_attach");
+        assert_eq!(
+            hooks.to_config(),
+            serde_json::json!({ "general": { "hooks": { "pre_attach": ["./power-on.sh"] } } })
+        );
+    }
+
+    #[test]
+    fn maps_env_names_to_known_keys() {
+        let paths = validate::known_paths();
+        assert_eq!(
+            find_env_path("CARGO_EMBED_PROBE_USB_VID", &paths),
+            Some("probe.usb_vid")
+        );
+        assert_eq!(
+            find_env_path("CARGO_EMBED_GENERAL_HOOKS_PRE_ATTACH", &paths),
+            Some("general.hooks.pre_attach")
+        );
+        assert_eq!(
+            find_env_path("CARGO_EMBED_RTT_CHANNELS", &paths),
+            Some("rtt.channels")
+        );
+        assert_eq!(find_env_path("CARGO_EMBED_PROBE_USB", &paths), None);
+        assert_eq!(find_env_path("CARGO_EMBED_LOG", &paths), None);
+
+        // Every key must have its own variable.
+        let mut names = paths.iter().map(|path| env_name(path)).collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), paths.len());
+    }
+}
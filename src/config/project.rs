@@ -0,0 +1,114 @@
+use anyhow::{anyhow, Context};
+use cargo_metadata::MetadataCommand;
+use std::path::{Path, PathBuf};
+
+/// The cargo workspace and package cargo-embed is run for.
+///
+/// Config files are looked up in the workspace root, the package directory and
+/// the current directory, and in the `package.metadata.embed` table of the package.
+#[derive(Debug, Default)]
+pub struct Project {
+    /// The root directory of the workspace.
+    pub workspace_root: Option<PathBuf>,
+    /// The directory of the selected package.
+    pub package_dir: Option<PathBuf>,
+    /// The path to the `Cargo.toml` of the selected package.
+    pub manifest_path: Option<PathBuf>,
+    /// The `package.metadata.embed` table of the selected package.
+    pub metadata: Option<serde_json::Value>,
+    /// The names of the binary targets of the selected package.
+    pub binaries: Vec<String>,
+    /// The target directory of the workspace.
+    pub target_dir: Option<PathBuf>,
+}
+
+impl Project {
+    /// Finds the workspace and package selected with `--manifest-path` and `--package`.
+    ///
+    /// Without `--package`, the package whose manifest is selected is used.
+    /// In a virtual workspace, only the workspace root is known then.
+    /// Outside of a cargo project, nothing is known and only the current directory is searched.
+    pub fn discover(manifest_path: Option<&Path>, package: Option<&str>) -> anyhow::Result<Self> {
+        let mut command = MetadataCommand::new();
+        command.no_deps();
+        if let Some(manifest_path) = manifest_path {
+            command.manifest_path(manifest_path);
+        }
+
+        let metadata = match command.exec() {
+            Ok(metadata) => metadata,
+            Err(_) if manifest_path.is_none() && package.is_none() => return Ok(Self::default()),
+            Err(error) => return Err(error).context("Failed to read the cargo metadata"),
+        };
+
+        let package = match package {
+            Some(name) => Some(
+                metadata
+                    .packages
+                    .iter()
+                    .filter(|package| metadata.workspace_members.contains(&package.id))
+                    .find(|package| package.name == name)
+                    .ok_or_else(|| {
+                        anyhow!("The package '{}' is not a member of the workspace", name)
+                    })?,
+            ),
+            None => {
+                // `Metadata::root_package` is not available without resolving dependencies,
+                // so look for the package with the selected manifest ourselves.
+                let selected = match manifest_path {
+                    Some(manifest_path) => Some(manifest_path.to_path_buf()),
+                    None => std::env::current_dir()?
+                        .ancestors()
+                        .map(|dir| dir.join("Cargo.toml"))
+                        .find(|manifest_path| manifest_path.is_file()),
+                }
+                .and_then(|manifest_path| manifest_path.canonicalize().ok());
+
+                metadata.packages.iter().find(|package| {
+                    package.manifest_path.canonicalize().ok().as_ref() == selected.as_ref()
+                })
+            }
+        };
+
+        Ok(Self {
+            workspace_root: Some(metadata.workspace_root.clone()),
+            package_dir: package
+                .and_then(|package| package.manifest_path.parent())
+                .map(Path::to_path_buf),
+            manifest_path: package.map(|package| package.manifest_path.clone()),
+            metadata: package
+                .and_then(|package| package.metadata.get("embed"))
+                .cloned(),
+            binaries: package
+                .iter()
+                .flat_map(|package| &package.targets)
+                .filter(|target| target.kind.iter().any(|kind| kind == "bin"))
+                .map(|target| target.name.clone())
+                .collect(),
+            target_dir: Some(metadata.target_directory.clone()),
+        })
+    }
+
+    /// Returns the directories which are searched for config files, from lowest to highest precedence.
+    ///
+    /// Directories are only returned once, even if the workspace root is also the package directory.
+    pub fn config_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs: Vec<PathBuf> = Vec::new();
+
+        let candidates = self
+            .workspace_root
+            .iter()
+            .chain(self.package_dir.iter())
+            .cloned()
+            .chain(std::env::current_dir().ok());
+
+        for dir in candidates {
+            let dir = dir.canonicalize().unwrap_or(dir);
+            if !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+
+        dirs
+    }
+}
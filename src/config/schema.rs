@@ -0,0 +1,158 @@
+use super::{Config, ProbeAlias, PROBE_ALIASES};
+use schemars::{
+    gen::{SchemaGenerator, SchemaSettings},
+    schema::{InstanceType, Schema, SchemaObject, SubschemaValidation},
+};
+use serde_json::{json, value::Value};
+use std::collections::HashMap;
+
+/// The sections of a config, which can be given partially in every config file.
+const SECTIONS: &[&str] = &["General", "Flashing", "Reset", "Probe", "Rtt", "Gdb"];
+
+/// Returns a JSON Schema of the config files, e.g. for validation and completion in editors.
+///
+/// A config file is a map of config names to configs, next to the `[probes]` table.
+/// As every config is merged with its parents, no key is required,
+/// but unknown keys are rejected just like when loading the files.
+/// The values of the built-in `default.toml` are given as schema defaults.
+pub fn schema() -> anyhow::Result<Value> {
+    let mut generator = SchemaGenerator::new(SchemaSettings::draft07());
+    let probe_alias = generator.subschema_for::<ProbeAlias>();
+    let root = generator.into_root_schema_for::<HashMap<String, Config>>();
+    let mut schema = serde_json::to_value(root)?;
+
+    schema["properties"] = json!({
+        PROBE_ALIASES: {
+            "description": "Probes with a name, which can be selected with 'probe.alias' or '--probe <name>'.",
+            "type": "object",
+            "additionalProperties": probe_alias,
+        }
+    });
+
+    schema["title"] = json!("cargo-embed config");
+    schema["description"] = json!(
+        "A cargo-embed config file (Embed.toml). Maps config names to configs, \
+         which derive from the \"default\" config unless specified otherwise."
+    );
+
+    let definitions = schema["definitions"]
+        .as_object_mut()
+        .expect("the generated schema has definitions");
+    // Like the sections, hooks can be given partially as the defaults fill in the rest.
+    for name in SECTIONS.iter().chain(&["Config", "Hooks"]) {
+        if let Some(definition) = definitions.get_mut(*name) {
+            definition
+                .as_object_mut()
+                .expect("struct schemas are objects")
+                .remove("required");
+            definition["additionalProperties"] = json!(false);
+        }
+    }
+    for name in &["ChannelConfig", "ImageConfig", "PatchConfig", "ProbeAlias"] {
+        if let Some(definition) = definitions.get_mut(*name) {
+            definition["additionalProperties"] = json!(false);
+        }
+    }
+
+    let defaults: toml::Value = toml::from_str(include_str!("default.toml"))?;
+    let defaults = serde_json::to_value(&defaults["default"])?;
+    for (section, values) in defaults.as_object().into_iter().flatten() {
+        let mut definition = SECTIONS
+            .iter()
+            .find(|name| name.eq_ignore_ascii_case(section))
+            .and_then(|name| definitions.get_mut(*name));
+
+        for (key, value) in values.as_object().into_iter().flatten() {
+            if let Some(property) = definition
+                .as_mut()
+                .and_then(|definition| definition["properties"].get_mut(key))
+            {
+                property["default"] = value.clone();
+            }
+        }
+    }
+
+    Ok(schema)
+}
+
+/// The schema of [`probe_rs::WireProtocol`], which does not implement `JsonSchema` itself.
+pub fn wire_protocol(_: &mut SchemaGenerator) -> Schema {
+    string_enum(&["Swd", "Jtag"])
+}
+
+/// The schema of [`log::Level`], which is parsed case-insensitively.
+pub fn log_level(_: &mut SchemaGenerator) -> Schema {
+    string_enum(&[
+        "ERROR", "WARN", "INFO", "DEBUG", "TRACE", "error", "warn", "info", "debug", "trace",
+    ])
+}
+
+/// The schema of [`Speed`](super::Speed), which is a number of kHz or "auto".
+pub fn speed(_: &mut SchemaGenerator) -> Schema {
+    SchemaObject {
+        subschemas: Some(Box::new(SubschemaValidation {
+            any_of: Some(vec![
+                SchemaObject {
+                    instance_type: Some(InstanceType::Integer.into()),
+                    format: Some("uint32".to_string()),
+                    ..SchemaObject::default()
+                }
+                .into(),
+                string_enum(&["auto"]),
+                SchemaObject {
+                    instance_type: Some(InstanceType::Null.into()),
+                    ..SchemaObject::default()
+                }
+                .into(),
+            ]),
+            ..SubschemaValidation::default()
+        })),
+        ..SchemaObject::default()
+    }
+    .into()
+}
+
+fn string_enum(values: &[&str]) -> Schema {
+    SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        enum_values: Some(values.iter().map(|value| json!(value)).collect()),
+        ..SchemaObject::default()
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::schema;
+    use serde_json::json;
+
+    #[test]
+    fn contains_defaults() {
+        let schema = schema().unwrap();
+        let definitions = &schema["definitions"];
+
+        assert_eq!(
+            schema["additionalProperties"]["$ref"],
+            json!("#/definitions/Config")
+        );
+        assert_eq!(
+            definitions["Probe"]["properties"]["protocol"]["default"],
+            json!("Swd")
+        );
+        assert_eq!(
+            definitions["Rtt"]["properties"]["timeout"]["default"],
+            json!(3000)
+        );
+        assert_eq!(definitions["Rtt"].get("required"), None);
+        assert_eq!(definitions["Gdb"]["additionalProperties"], json!(false));
+        assert_eq!(definitions["Hooks"].get("required"), None);
+        assert_eq!(
+            schema["properties"]["probes"]["additionalProperties"]["$ref"],
+            json!("#/definitions/ProbeAlias")
+        );
+        assert_eq!(
+            definitions["DataFormat"]["enum"],
+            json!(["String", "BinaryLE", "Defmt"])
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use super::{leaves, ResolvedConfig};
+use anyhow::Context;
+use serde_json::value::Value;
+use std::collections::BTreeMap;
+
+impl ResolvedConfig {
+    /// Renders the resolved config as TOML, nested below its config name,
+    /// so the output can be used as an `Embed.toml` again.
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        let mut configs = BTreeMap::new();
+        configs.insert(&self.name, &self.config);
+
+        let value = toml::Value::try_from(configs).context("Failed to convert config to TOML")?;
+        Ok(toml::to_string_pretty(&value)?)
+    }
+
+    /// Renders the resolved config as JSON, nested below its config name.
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        let mut configs = BTreeMap::new();
+        configs.insert(&self.name, &self.config);
+
+        Ok(serde_json::to_string_pretty(&configs)?)
+    }
+
+    /// Renders every key of the resolved config together with the file and config which set it.
+    ///
+    /// Keys without a value are omitted, just like in the TOML output.
+    pub fn to_annotated_toml(&self) -> anyhow::Result<String> {
+        let config = serde_json::to_value(&self.config)?;
+
+        let lines = leaves(&config)
+            .into_iter()
+            .filter(|(_, value)| !value.is_null())
+            .map(|(key, value)| {
+                let origin = match self.origins.get(&key) {
+                    Some(origin) if origin.config == self.name => origin.source.clone(),
+                    Some(origin) => format!("{} (config \"{}\")", origin.source, origin.config),
+                    None => "unknown".to_string(),
+                };
+                (format!("{} = {}", key, inline_toml(value)), origin)
+            })
+            .collect::<Vec<_>>();
+
+        let width = lines.iter().map(|(line, _)| line.len()).max().unwrap_or(0);
+
+        let mut output = format!("[{}]\n", self.name);
+        for (line, origin) in lines {
+            output += &format!("{:width$}  # {}\n", line, origin, width = width);
+        }
+        Ok(output)
+    }
+
+    /// Renders every key of the resolved config as a JSON object
+    /// holding its value and the file and config which set it.
+    pub fn to_annotated_json(&self) -> anyhow::Result<String> {
+        let config = serde_json::to_value(&self.config)?;
+
+        let annotated = leaves(&config)
+            .into_iter()
+            .filter(|(_, value)| !value.is_null())
+            .map(|(key, value)| {
+                let origin = self.origins.get(&key);
+                let annotated = serde_json::json!({
+                    "value": value,
+                    "config": origin.map(|origin| &origin.config),
+                    "source": origin.map(|origin| &origin.source),
+                });
+                (key, annotated)
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        Ok(serde_json::to_string_pretty(&annotated)?)
+    }
+}
+
+/// Renders a value the way it would be written inline in a TOML file.
+fn inline_toml(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::Bool(value) => value.to_string(),
+        Value::Number(value) => value.to_string(),
+        // JSON string escapes are valid in TOML basic strings as well.
+        Value::String(_) => value.to_string(),
+        Value::Array(values) => format!(
+            "[{}]",
+            values
+                .iter()
+                .map(inline_toml)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Value::Object(map) => format!(
+            "{{ {} }}",
+            map.iter()
+                .filter(|(_, value)| !value.is_null())
+                .map(|(key, value)| format!("{} = {}", key, inline_toml(value)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
@@ -0,0 +1,286 @@
+use super::{
+    Config, Flashing, Gdb, General, Hooks, ImageConfig, PatchConfig, Probe, ProbeAlias, Reset, Rtt,
+    PROBE_ALIASES,
+};
+use crate::rttui::channel::ChannelConfig;
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde_json::value::Value;
+use std::collections::HashMap;
+use std::fmt;
+
+/// A key in a config file which does not belong to any config option.
+#[derive(Debug, PartialEq)]
+pub struct UnknownKey {
+    /// The full path of the key, e.g. `default.rtt.show_timestamp`.
+    pub path: String,
+    /// The known key which is the most similar to the unknown one, if any is similar enough.
+    pub suggestion: Option<&'static str>,
+}
+
+impl fmt::Display for UnknownKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown key '{}'", self.path)?;
+        if let Some(suggestion) = self.suggestion {
+            write!(f, ", did you mean '{}'?", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns all keys of the given configs which are not known to [`Config`],
+/// and all keys of the probe aliases which are not known to [`ProbeAlias`].
+pub fn unknown_keys(configs: &HashMap<String, Value>) -> Vec<UnknownKey> {
+    let sections: &[(&str, &'static [&'static str])] = &[
+        ("general", field_names::<General>()),
+        ("flashing", field_names::<Flashing>()),
+        ("reset", field_names::<Reset>()),
+        ("probe", field_names::<Probe>()),
+        ("rtt", field_names::<Rtt>()),
+        ("gdb", field_names::<Gdb>()),
+    ];
+
+    let mut names = configs.keys().collect::<Vec<_>>();
+    names.sort();
+
+    let mut unknown = Vec::new();
+    for name in names {
+        let config = match &configs[name] {
+            Value::Object(config) => config,
+            _ => continue,
+        };
+
+        if name == PROBE_ALIASES {
+            for (alias, probe) in config {
+                if let Value::Object(keys) = probe {
+                    check_keys(
+                        &format!("{}.{}", name, alias),
+                        keys.keys(),
+                        field_names::<ProbeAlias>(),
+                        &mut unknown,
+                    );
+                }
+            }
+            continue;
+        }
+
+        check_keys(name, config.keys(), field_names::<Config>(), &mut unknown);
+
+        for (section, known) in sections {
+            if let Some(Value::Object(keys)) = config.get(*section) {
+                check_keys(
+                    &format!("{}.{}", name, section),
+                    keys.keys(),
+                    known,
+                    &mut unknown,
+                );
+            }
+        }
+
+        if let Some(Value::Array(channels)) = config.get("rtt").and_then(|rtt| rtt.get("channels"))
+        {
+            for (i, channel) in channels.iter().enumerate() {
+                if let Value::Object(keys) = channel {
+                    check_keys(
+                        &format!("{}.rtt.channels[{}]", name, i),
+                        keys.keys(),
+                        field_names::<ChannelConfig>(),
+                        &mut unknown,
+                    );
+                }
+            }
+        }
+
+        if let Some(Value::Object(keys)) = config
+            .get("general")
+            .and_then(|general| general.get("hooks"))
+        {
+            check_keys(
+                &format!("{}.general.hooks", name),
+                keys.keys(),
+                field_names::<Hooks>(),
+                &mut unknown,
+            );
+        }
+
+        if let Some(Value::Array(images)) = config
+            .get("flashing")
+            .and_then(|flashing| flashing.get("images"))
+        {
+            for (i, image) in images.iter().enumerate() {
+                if let Value::Object(keys) = image {
+                    check_keys(
+                        &format!("{}.flashing.images[{}]", name, i),
+                        keys.keys(),
+                        field_names::<ImageConfig>(),
+                        &mut unknown,
+                    );
+                }
+            }
+        }
+
+        if let Some(Value::Array(patches)) = config
+            .get("flashing")
+            .and_then(|flashing| flashing.get("patch"))
+        {
+            for (i, patch) in patches.iter().enumerate() {
+                if let Value::Object(keys) = patch {
+                    check_keys(
+                        &format!("{}.flashing.patch[{}]", name, i),
+                        keys.keys(),
+                        field_names::<PatchConfig>(),
+                        &mut unknown,
+                    );
+                }
+            }
+        }
+    }
+
+    unknown
+}
+
+/// Returns the dotted paths of all keys a single value can be given for,
+/// e.g. `probe.speed` or `general.hooks.pre_attach`.
+///
+/// Keys which hold a list of tables, like `rtt.channels`, are included as a whole.
+pub fn known_paths() -> Vec<String> {
+    let sections: &[(&str, &'static [&'static str])] = &[
+        ("general", field_names::<General>()),
+        ("general.hooks", field_names::<Hooks>()),
+        ("flashing", field_names::<Flashing>()),
+        ("reset", field_names::<Reset>()),
+        ("probe", field_names::<Probe>()),
+        ("rtt", field_names::<Rtt>()),
+        ("gdb", field_names::<Gdb>()),
+    ];
+
+    sections
+        .iter()
+        .flat_map(|(section, keys)| keys.iter().map(move |key| format!("{}.{}", section, key)))
+        .collect()
+}
+
+/// Adds every key which is not contained in `known` to `unknown`.
+fn check_keys<'a>(
+    path: &str,
+    keys: impl Iterator<Item = &'a String>,
+    known: &'static [&'static str],
+    unknown: &mut Vec<UnknownKey>,
+) {
+    let mut keys = keys.collect::<Vec<_>>();
+    keys.sort();
+
+    for key in keys {
+        if !known.contains(&key.as_str()) {
+            unknown.push(UnknownKey {
+                path: format!("{}.{}", path, key),
+                suggestion: closest(key, known),
+            });
+        }
+    }
+}
+
+/// Returns the known key which is most similar to `key`, if it is similar enough to be a typo.
+fn closest(key: &str, known: &'static [&'static str]) -> Option<&'static str> {
+    known
+        .iter()
+        .map(|candidate| (strsim::jaro_winkler(key, candidate), *candidate))
+        .filter(|(similarity, _)| *similarity > 0.8)
+        .max_by(|a, b| a.0.partial_cmp(&b.0).unwrap())
+        .map(|(_, candidate)| candidate)
+}
+
+/// Returns the names of all fields of the struct `T`, as serde knows them.
+fn field_names<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    let mut fields = &[][..];
+    let _ = T::deserialize(FieldNames(&mut fields));
+    fields
+}
+
+/// A deserializer which records the field names of the struct it is asked to deserialize
+/// and then aborts the deserialization.
+struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+impl<'de, 'a> Deserializer<'de> for FieldNames<'a> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("only structs are supported"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = fields;
+        Err(de::Error::custom("field names recorded"))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{unknown_keys, UnknownKey};
+
+    #[test]
+    fn suggests_closest_keys() {
+        let configs = serde_json::from_str(
+            r#"{
+                "default": {
+                    "rtt": {
+                        "show_timestamp": false,
+                        "channels": [{ "up": 0, "formt": "Defmt" }]
+                    },
+                    "prob": { "speed": 1000 },
+                    "flashing": {
+                        "images": [{ "path": "mcuboot.hex", "ofset": 0 }],
+                        "patch": [{ "symbol": "SERIAL_NUMBER", "valeu": "1" }]
+                    },
+                    "general": { "hooks": { "pre_atach": [] } },
+                    "gdb": { "something_else": true }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            unknown_keys(&configs),
+            [
+                UnknownKey {
+                    path: "default.prob".to_string(),
+                    suggestion: Some("probe"),
+                },
+                UnknownKey {
+                    path: "default.rtt.show_timestamp".to_string(),
+                    suggestion: Some("show_timestamps"),
+                },
+                UnknownKey {
+                    path: "default.gdb.something_else".to_string(),
+                    suggestion: None,
+                },
+                UnknownKey {
+                    path: "default.rtt.channels[0].formt".to_string(),
+                    suggestion: Some("format"),
+                },
+                UnknownKey {
+                    path: "default.general.hooks.pre_atach".to_string(),
+                    suggestion: Some("pre_attach"),
+                },
+                UnknownKey {
+                    path: "default.flashing.images[0].ofset".to_string(),
+                    suggestion: Some("offset"),
+                },
+                UnknownKey {
+                    path: "default.flashing.patch[0].valeu".to_string(),
+                    suggestion: Some("value"),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,526 @@
+use anyhow::{anyhow, bail, Result};
+use colored::*;
+use probe_rs::{config::TargetSelector, DebugProbeInfo, Probe, Session};
+use probe_rs_cli_util::{build_artifact, logging};
+use std::{
+    fs::File,
+    net::TcpListener,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::{Config, Speed},
+    probes,
+    probes::Selection,
+    rttui,
+};
+
+/// The address the GDB server listens on if `gdb.gdb_connection_string` is not set.
+const DEFAULT_GDB_CONNECTION_STRING: &str = "127.0.0.1:1337";
+
+/// The outcome of every check `cargo embed doctor` ran.
+#[derive(Debug, Default)]
+struct Report {
+    passed: usize,
+    failed: usize,
+}
+
+impl Report {
+    fn pass(&mut self, check: &str, detail: impl AsRef<str>) {
+        self.passed += 1;
+        logging::println(format!(
+            "{} {}: {}",
+            format!("{:>12}", "Pass").green().bold(),
+            check,
+            detail.as_ref()
+        ));
+    }
+
+    fn fail(&mut self, check: &str, error: &anyhow::Error, hint: impl AsRef<str>) {
+        self.failed += 1;
+        logging::println(format!(
+            "{} {}: {:#}",
+            format!("{:>12}", "Fail").red().bold(),
+            check,
+            error
+        ));
+        logging::println(format!(
+            "{} {}",
+            format!("{:>12}", "Hint").cyan(),
+            hint.as_ref()
+        ));
+    }
+
+    fn note(&mut self, check: &str, detail: impl AsRef<str>) {
+        logging::println(format!(
+            "{} {}: {}",
+            format!("{:>12}", "Note").yellow().bold(),
+            check,
+            detail.as_ref()
+        ));
+    }
+
+    fn skip(&mut self, check: &str, reason: &str) {
+        logging::println(format!(
+            "{} {}: {}",
+            format!("{:>12}", "Skip").yellow().bold(),
+            check,
+            reason
+        ));
+    }
+}
+
+/// Goes through every step of flashing and debugging a target one by one
+/// and reports for each whether it works, with a hint on how to fix it if not.
+///
+/// Steps which depend on a failed step are skipped.
+/// Returns an error if any check failed.
+pub fn run(
+    config: &Config,
+    selection: Option<&Selection>,
+    chip: TargetSelector,
+    work_dir: &Path,
+    build_args: &[String],
+) -> Result<()> {
+    let mut report = Report::default();
+
+    let probe = check_probe_connected(&mut report, selection);
+
+    #[cfg(target_os = "linux")]
+    if let Some(info) = &probe {
+        check_udev(&mut report, info);
+    }
+
+    match &probe {
+        Some(info) => check_target(&mut report, config, info, chip),
+        None => {
+            for check in &[
+                "open probe",
+                "select protocol",
+                "set speed",
+                "attach",
+                "attach under reset",
+            ] {
+                report.skip(check, "no probe");
+            }
+        }
+    }
+
+    check_rtt_symbol(&mut report, work_dir, build_args);
+    check_gdb_port(&mut report, config);
+
+    if report.failed > 0 {
+        bail!(
+            "{} of {} checks failed.",
+            report.failed,
+            report.passed + report.failed
+        );
+    }
+    logging::println(format!(
+        "    {} all {} checks passed",
+        "Finished".green().bold(),
+        report.passed
+    ));
+    Ok(())
+}
+
+/// Checks that the selected probe, or exactly one probe if none is selected, is connected.
+fn check_probe_connected(
+    report: &mut Report,
+    selection: Option<&Selection>,
+) -> Option<DebugProbeInfo> {
+    const CHECK: &str = "probe connected";
+    let list = Probe::list_all();
+
+    let found = match selection {
+        Some(selection) => list
+            .iter()
+            .find(|info| selection.matches(info))
+            .ok_or_else(|| anyhow!("The probe {} is not connected", selection)),
+        None => match list.len() {
+            0 => Err(anyhow!("No supported probe was found")),
+            1 => Ok(&list[0]),
+            _ => Err(anyhow!(
+                "{} probes were found and none is selected",
+                list.len()
+            )),
+        },
+    };
+
+    match found {
+        Ok(info) => {
+            report.pass(CHECK, probes::describe(info));
+            Some(info.clone())
+        }
+        Err(error) => {
+            let hint = if list.len() > 1 {
+                "Select a probe with '--probe', 'probe.alias' or 'probe.usb_vid' and 'probe.usb_pid'. \
+                 'cargo embed probes' lists all connected probes."
+            } else {
+                "Check the USB cable and that the probe is supported by probe-rs. \
+                 'cargo embed probes' lists all connected probes."
+            };
+            report.fail(CHECK, &error, hint);
+            None
+        }
+    }
+}
+
+/// Checks that the USB device node of the probe can be opened by the current user.
+///
+/// Without udev rules, probes are only accessible to root on most distributions.
+#[cfg(target_os = "linux")]
+fn check_udev(report: &mut Report, info: &DebugProbeInfo) {
+    const CHECK: &str = "udev permissions";
+
+    let nodes = device_nodes(Path::new("/sys/bus/usb/devices"), info);
+    if nodes.is_empty() {
+        report.skip(CHECK, "the USB device node of the probe was not found");
+        return;
+    }
+
+    let inaccessible = nodes
+        .iter()
+        .filter(|node| {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(node)
+                .is_err()
+        })
+        .map(|node| node.display().to_string())
+        .collect::<Vec<_>>();
+
+    if inaccessible.is_empty() {
+        report.pass(CHECK, "the probe is accessible without root");
+    } else {
+        report.fail(
+            CHECK,
+            &anyhow!("{} cannot be opened by the current user", inaccessible.join(", ")),
+            format!(
+                "Install udev rules for the probe, e.g. a file in /etc/udev/rules.d/ containing\n\
+                 {:>12} ATTRS{{idVendor}}==\"{:04x}\", ATTRS{{idProduct}}==\"{:04x}\", MODE=\"660\", GROUP=\"plugdev\", TAG+=\"uaccess\"\n\
+                 {:>12} then run 'sudo udevadm control --reload-rules && sudo udevadm trigger' and reconnect the probe.",
+                "", info.vendor_id, info.product_id, ""
+            ),
+        );
+    }
+}
+
+/// Returns the `/dev/bus/usb/BBB/DDD` nodes of all USB devices in `sysfs` with the VID:PID of the probe.
+#[cfg(target_os = "linux")]
+fn device_nodes(sysfs: &Path, info: &DebugProbeInfo) -> Vec<PathBuf> {
+    let read = |device: &Path, attribute: &str| {
+        std::fs::read_to_string(device.join(attribute))
+            .ok()
+            .map(|value| value.trim().to_string())
+    };
+    let matches_id = |device: &Path, attribute: &str, id: u16| {
+        read(device, attribute).and_then(|value| u16::from_str_radix(&value, 16).ok()) == Some(id)
+    };
+
+    let entries = match std::fs::read_dir(sysfs) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut nodes = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|device| {
+            matches_id(device, "idVendor", info.vendor_id)
+                && matches_id(device, "idProduct", info.product_id)
+                && (info.serial_number.is_none() || read(device, "serial") == info.serial_number)
+        })
+        .filter_map(|device| {
+            let bus = read(&device, "busnum")?.parse::<u32>().ok()?;
+            let dev = read(&device, "devnum")?.parse::<u32>().ok()?;
+            Some(PathBuf::from(format!("/dev/bus/usb/{:03}/{:03}", bus, dev)))
+        })
+        .collect::<Vec<_>>();
+    nodes.sort();
+    nodes
+}
+
+/// Goes through opening the probe, selecting the protocol, setting the speed and attaching
+/// to the target, with and without `connect_under_reset`.
+fn check_target(report: &mut Report, config: &Config, info: &DebugProbeInfo, chip: TargetSelector) {
+    if check_probe(report, config, info) {
+        check_attach(report, config, info, chip);
+    } else {
+        report.skip("attach", "the probe is not usable");
+        report.skip("attach under reset", "the probe is not usable");
+    }
+}
+
+/// Opens the probe, selects the protocol and sets the speed. Returns whether all of it worked.
+///
+/// The probe is closed again when this returns, so it can be opened for attaching.
+fn check_probe(report: &mut Report, config: &Config, info: &DebugProbeInfo) -> bool {
+    let mut probe = match Probe::open(info) {
+        Ok(probe) => {
+            report.pass("open probe", probe.get_name());
+            probe
+        }
+        Err(error) => {
+            let hint = if cfg!(target_os = "linux") {
+                "Make sure no other program (e.g. another debugger) uses the probe \
+                 and that the udev rules are installed."
+            } else {
+                "Make sure no other program (e.g. another debugger) uses the probe \
+                 and that its driver is installed."
+            };
+            report.fail("open probe", &error.into(), hint);
+            report.skip("select protocol", "the probe could not be opened");
+            report.skip("set speed", "the probe could not be opened");
+            return false;
+        }
+    };
+
+    if let Err(error) = probe.select_protocol(config.probe.protocol) {
+        report.fail(
+            "select protocol",
+            &error.into(),
+            format!(
+                "The probe may not support {:?}. Try another 'probe.protocol'.",
+                config.probe.protocol
+            ),
+        );
+        report.skip("set speed", "the protocol could not be selected");
+        return false;
+    }
+    report.pass("select protocol", format!("{:?}", config.probe.protocol));
+
+    match config.probe.speed {
+        Some(Speed::Khz(speed)) => match probe.set_speed(speed) {
+            Ok(actual) if actual < speed => report.pass(
+                "set speed",
+                format!("{} kHz instead of the configured {} kHz", actual, speed),
+            ),
+            Ok(actual) => report.pass("set speed", format!("{} kHz", actual)),
+            Err(error) => {
+                report.fail(
+                    "set speed",
+                    &error.into(),
+                    "Try a lower 'probe.speed' or remove it to use the default speed of the probe.",
+                );
+                return false;
+            }
+        },
+        Some(Speed::Auto) => report.pass(
+            "set speed",
+            format!(
+                "auto, checked with the default of the probe ({} kHz). \
+                 'cargo embed probe-speed' measures the fastest reliable speed.",
+                probe.speed_khz()
+            ),
+        ),
+        None => report.pass(
+            "set speed",
+            format!("{} kHz (default of the probe)", probe.speed_khz()),
+        ),
+    }
+
+    true
+}
+
+/// Attaches to the target both without and under reset, as either may be the one which works.
+///
+/// Only the way `general.connect_under_reset` selects has to work, a failure of the other
+/// one is noted. The hints depend on which of the two work.
+fn check_attach(report: &mut Report, config: &Config, info: &DebugProbeInfo, chip: TargetSelector) {
+    // Attaching consumes the probe, so each attempt starts with a freshly opened one.
+    let open = || -> Result<Probe> {
+        let mut probe = Probe::open(info)?;
+        probe.select_protocol(config.probe.protocol)?;
+        if let Some(Speed::Khz(speed)) = config.probe.speed {
+            probe.set_speed(speed)?;
+        }
+        Ok(probe)
+    };
+
+    // The sessions are closed right away, so the probe can be opened again.
+    let plain = open()
+        .and_then(|probe| Ok(probe.attach(chip.clone())?))
+        .map(|session| attached_to(&session, &chip));
+    let under_reset = open()
+        .and_then(|probe| Ok(probe.attach_under_reset(chip.clone())?))
+        .map(|session| attached_to(&session, &chip));
+
+    let connect_under_reset = config.general.connect_under_reset;
+    let hint = attach_hint(connect_under_reset, plain.is_ok(), under_reset.is_ok());
+    for (check, result) in [("attach", plain), ("attach under reset", under_reset)] {
+        match (result, hint) {
+            (Ok(detail), _) => report.pass(check, detail),
+            (Err(error), Some(hint)) => report.fail(check, &error, hint),
+            (Err(error), None) => report.note(
+                check,
+                format!(
+                    "{:#}, which is not needed as 'general.connect_under_reset' is {}",
+                    error,
+                    if connect_under_reset {
+                        "enabled"
+                    } else {
+                        "disabled"
+                    }
+                ),
+            ),
+        }
+    }
+}
+
+/// Returns the hint for failing to attach, depending on whether attaching without
+/// and under reset work, or `None` if the configured way works.
+fn attach_hint(connect_under_reset: bool, plain: bool, under_reset: bool) -> Option<&'static str> {
+    match (connect_under_reset, plain, under_reset) {
+        (_, false, false) => Some(
+            "Check the wiring and power of the target and that 'general.chip' matches it. \
+             Attaching under reset needs the reset pin (nRST) of the probe to be connected to the target.",
+        ),
+        (false, false, true) => Some(
+            "Attaching only works under reset, e.g. because the firmware disables the debug pins \
+             or sends the chip to sleep. Enable 'general.connect_under_reset'.",
+        ),
+        (true, true, false) => Some(
+            "Attaching only works without reset. Connect the reset pin (nRST) of the probe \
+             to the target, or disable 'general.connect_under_reset'.",
+        ),
+        _ => None,
+    }
+}
+
+/// Describes the chip a session is attached to.
+fn attached_to(session: &Session, chip: &TargetSelector) -> String {
+    let name = match chip {
+        TargetSelector::Unspecified(name) => Some(name.clone()),
+        TargetSelector::Specified(target) => Some(target.identifier.chip_name.clone()),
+        TargetSelector::Auto => probes::chip_name(session),
+    };
+    match name {
+        Some(name) => format!("attached to {}", name),
+        None => "attached to the auto-detected chip".to_string(),
+    }
+}
+
+/// Builds the firmware and checks that it contains an RTT control block.
+fn check_rtt_symbol(report: &mut Report, work_dir: &Path, build_args: &[String]) {
+    const CHECK: &str = "RTT symbol";
+
+    let elf = match build_artifact(work_dir, build_args) {
+        Ok(elf) => elf,
+        Err(error) => {
+            report.fail(
+                "build firmware",
+                &error,
+                "Fix the build, or pass the same cargo arguments as to 'cargo embed'.",
+            );
+            report.skip(CHECK, "the firmware could not be built");
+            return;
+        }
+    };
+    report.pass("build firmware", elf.display().to_string());
+
+    let symbol = File::open(&elf)
+        .map(|mut file| rttui::app::App::get_rtt_symbol(&mut file))
+        .map_err(anyhow::Error::from);
+    match symbol {
+        Ok(Some(address)) => report.pass(CHECK, format!("_SEGGER_RTT at {:#010x}", address)),
+        Ok(None) => report.fail(
+            CHECK,
+            &anyhow!("{} contains no _SEGGER_RTT symbol", elf.display()),
+            "Use an RTT implementation like rtt-target or defmt-rtt in the firmware \
+             and make sure it is initialized, so the linker keeps the control block.",
+        ),
+        Err(error) => report.fail(
+            CHECK,
+            &error.context(format!("Failed to open {}", elf.display())),
+            "Check that the build produced an ELF file.",
+        ),
+    }
+}
+
+/// Checks that the GDB server could listen on `gdb.gdb_connection_string`.
+fn check_gdb_port(report: &mut Report, config: &Config) {
+    const CHECK: &str = "GDB port";
+
+    let address = config
+        .gdb
+        .gdb_connection_string
+        .as_deref()
+        .unwrap_or(DEFAULT_GDB_CONNECTION_STRING);
+
+    match TcpListener::bind(address) {
+        Ok(_) => report.pass(CHECK, format!("{} is available", address)),
+        Err(error) => report.fail(
+            CHECK,
+            &anyhow::Error::from(error).context(format!("Cannot listen on {}", address)),
+            "Stop the program which uses the port (e.g. another cargo-embed or a GDB server) \
+             or set 'gdb.gdb_connection_string' to a free address.",
+        ),
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::{attach_hint, device_nodes};
+    use crate::test_util::TempDir;
+    use probe_rs::{DebugProbeInfo, DebugProbeType};
+    use std::path::PathBuf;
+
+    #[test]
+    fn finds_device_nodes() {
+        let temp = TempDir::new("finds-device-nodes");
+        let sysfs = temp.path();
+        for (device, vid, pid, serial, bus, dev) in &[
+            ("1-2", "1366", "1015", "000683000000", "1", "7"),
+            ("1-3", "1366", "1015", "000683999999", "1", "9"),
+            ("2-1", "0483", "374b", "0669FF", "2", "12"),
+        ] {
+            for (attribute, value) in &[
+                ("idVendor", vid),
+                ("idProduct", pid),
+                ("serial", serial),
+                ("busnum", bus),
+                ("devnum", dev),
+            ] {
+                temp.write(&format!("{}/{}", device, attribute), format!("{}\n", value));
+            }
+        }
+
+        let mut info = DebugProbeInfo {
+            identifier: "J-Link".to_string(),
+            vendor_id: 0x1366,
+            product_id: 0x1015,
+            serial_number: Some("000683000000".to_string()),
+            probe_type: DebugProbeType::JLink,
+        };
+        let selected = device_nodes(sysfs, &info);
+        info.serial_number = None;
+        let all = device_nodes(sysfs, &info);
+
+        assert_eq!(selected, [PathBuf::from("/dev/bus/usb/001/007")]);
+        assert_eq!(
+            all,
+            [
+                PathBuf::from("/dev/bus/usb/001/007"),
+                PathBuf::from("/dev/bus/usb/001/009")
+            ]
+        );
+    }
+
+    #[test]
+    fn hints_from_both_ways_of_attaching() {
+        // The configured way works.
+        assert_eq!(attach_hint(false, true, false), None);
+        assert_eq!(attach_hint(true, false, true), None);
+
+        assert!(attach_hint(false, false, true)
+            .unwrap()
+            .contains("Enable 'general.connect_under_reset'"));
+        assert!(attach_hint(true, true, false)
+            .unwrap()
+            .contains("disable 'general.connect_under_reset'"));
+        assert!(attach_hint(true, false, false)
+            .unwrap()
+            .contains("Check the wiring"));
+    }
+}
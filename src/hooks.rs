@@ -0,0 +1,216 @@
+use anyhow::{bail, Context, Result};
+use colored::*;
+use probe_rs_cli_util::logging;
+use std::{
+    collections::BTreeMap,
+    process::Command,
+    sync::{Arc, Mutex},
+};
+
+use crate::config::Hooks;
+
+/// The steps of cargo-embed at which hooks are run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hook {
+    PreAttach,
+    PostFlash,
+    PostReset,
+    OnExit,
+}
+
+impl Hook {
+    fn name(self) -> &'static str {
+        match self {
+            Hook::PreAttach => "pre_attach",
+            Hook::PostFlash => "post_flash",
+            Hook::PostReset => "post_reset",
+            Hook::OnExit => "on_exit",
+        }
+    }
+
+    fn commands(self, hooks: &Hooks) -> &[String] {
+        match self {
+            Hook::PreAttach => &hooks.pre_attach,
+            Hook::PostFlash => &hooks.post_flash,
+            Hook::PostReset => &hooks.post_reset,
+            Hook::OnExit => &hooks.on_exit,
+        }
+    }
+}
+
+/// The hooks of the config with the environment variables describing the session.
+///
+/// The variables start with `EMBED_`, as `CARGO_EMBED_` variables are config overrides.
+#[derive(Debug, Clone)]
+struct Commands {
+    hooks: Hooks,
+    env: BTreeMap<&'static str, String>,
+}
+
+impl Commands {
+    /// Runs the commands of the hook one after another and stops at the first which fails.
+    fn run(&self, hook: Hook) -> Result<()> {
+        for command in hook.commands(&self.hooks) {
+            logging::println(format!(
+                "     {} {} hook `{}`",
+                "Running".green().bold(),
+                hook.name(),
+                command
+            ));
+            let status = shell(command)
+                .envs(&self.env)
+                .env("EMBED_HOOK", hook.name())
+                .status()
+                .with_context(|| format!("Failed to run the {} hook `{}`", hook.name(), command))?;
+            if !status.success() {
+                bail!(
+                    "The {} hook `{}` failed with {}, aborting.",
+                    hook.name(),
+                    command,
+                    status
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns a command which runs `command` in the shell of the platform.
+fn shell(command: &str) -> Command {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command);
+    shell
+}
+
+/// Runs the hooks of the config.
+///
+/// No hooks are run until [`Runner::init`] was called with the hooks of the loaded config.
+/// Clones share the hooks, so the Ctrl-C handler can run `on_exit` with a clone.
+#[derive(Debug, Clone, Default)]
+pub struct Runner(Arc<Mutex<Option<Commands>>>);
+
+impl Runner {
+    /// Sets the hooks which are run and the profile they are run for.
+    pub fn init(&self, hooks: &Hooks, profile: &str) {
+        let mut env = BTreeMap::new();
+        env.insert("EMBED_PROFILE", profile.to_string());
+        *self.0.lock().unwrap() = Some(Commands {
+            hooks: hooks.clone(),
+            env,
+        });
+    }
+
+    /// Sets an environment variable for all hooks which are run from now on.
+    pub fn set_env(&self, name: &'static str, value: impl Into<String>) {
+        if let Some(commands) = self.0.lock().unwrap().as_mut() {
+            commands.env.insert(name, value.into());
+        }
+    }
+
+    /// Runs the commands of the hook, returning an error if any of them fails.
+    pub fn run(&self, hook: Hook) -> Result<()> {
+        // The hooks are not run under the lock, so they cannot block setting variables.
+        let commands = self.0.lock().unwrap().clone();
+        match commands {
+            Some(commands) => commands.run(hook),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the `on_exit` hooks, at most once.
+    pub fn run_on_exit(&self) -> Result<()> {
+        let commands = self.0.lock().unwrap().take();
+        match commands {
+            Some(commands) => commands.run(Hook::OnExit),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns a command which runs `command` in the shell, with the environment variables of the hooks.
+    pub fn shell(&self, command: &str) -> Command {
+        let mut shell = shell(command);
+        if let Some(commands) = self.0.lock().unwrap().as_ref() {
+            shell.envs(&commands.env);
+        }
+        shell
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::{Hook, Runner};
+    use crate::{config::Hooks, test_util::TempDir};
+
+    fn hooks() -> Hooks {
+        Hooks {
+            pre_attach: vec![],
+            post_flash: vec![],
+            post_reset: vec![],
+            on_exit: vec![],
+        }
+    }
+
+    #[test]
+    fn runs_hooks_with_environment() {
+        let dir = TempDir::new("runs-hooks");
+        let path = dir.path().join("hook");
+        let runner = Runner::default();
+        runner.init(
+            &Hooks {
+                pre_attach: vec![format!(
+                    "echo \"$EMBED_HOOK $EMBED_PROFILE $EMBED_CHIP\" > {}",
+                    path.display()
+                )],
+                ..hooks()
+            },
+            "default",
+        );
+        runner.set_env("EMBED_CHIP", "nRF52840_xxAA");
+
+        runner.run(Hook::PreAttach).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "pre_attach default nRF52840_xxAA\n"
+        );
+    }
+
+    #[test]
+    fn stops_at_the_first_failing_command() {
+        let runner = Runner::default();
+        runner.init(
+            &Hooks {
+                post_flash: vec!["true".to_string(), "exit 3".to_string()],
+                ..hooks()
+            },
+            "default",
+        );
+
+        let error = runner.run(Hook::PostFlash).unwrap_err().to_string();
+        assert!(error.starts_with("The post_flash hook `exit 3` failed with exit status: 3"));
+        assert!(runner.run(Hook::PostReset).is_ok());
+    }
+
+    #[test]
+    fn runs_on_exit_once() {
+        let runner = Runner::default();
+        assert!(runner.run_on_exit().is_ok());
+
+        runner.init(
+            &Hooks {
+                on_exit: vec!["exit 1".to_string()],
+                ..hooks()
+            },
+            "default",
+        );
+        assert!(runner.clone().run_on_exit().is_err());
+        assert!(runner.run_on_exit().is_ok());
+    }
+}
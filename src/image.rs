@@ -0,0 +1,633 @@
+use anyhow::{anyhow, bail, Context, Result};
+use goblin::elf::{program_header::PT_LOAD, Elf};
+use probe_rs::{
+    flashing::{download_file_with_options, DownloadOptions, FlashProgress, Format},
+    MemoryInterface, Session,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::TryFrom,
+    fs::{self, File},
+    io::Read,
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// The format of an image which is flashed instead of, or together with, the cargo build artifact.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    Elf,
+    Hex,
+    Bin,
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "elf" => Ok(Self::Elf),
+            "hex" | "ihex" => Ok(Self::Hex),
+            "bin" => Ok(Self::Bin),
+            _ => Err(format!(
+                "'{}' is not an image format, use 'elf', 'hex' or 'bin'",
+                s
+            )),
+        }
+    }
+}
+
+impl ImageFormat {
+    /// Detects the format of the image at `path` from its extension, or from its contents
+    /// if the extension is not known.
+    ///
+    /// Files which are neither ELF nor Intel HEX are raw binaries.
+    pub fn detect(path: &Path) -> Result<Self> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("elf") | Some("axf") | Some("out") => return Ok(Self::Elf),
+            Some("hex") | Some("ihex") => return Ok(Self::Hex),
+            Some("bin") => return Ok(Self::Bin),
+            _ => {}
+        }
+
+        let mut start = [0; 4];
+        let read = File::open(path)
+            .and_then(|mut file| file.read(&mut start))
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Ok(match &start[..read] {
+            [0x7f, b'E', b'L', b'F'] => Self::Elf,
+            [b':', ..] => Self::Hex,
+            _ => Self::Bin,
+        })
+    }
+}
+
+/// A continuous block of data which is flashed to `address`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    /// Returns the addresses the segment is flashed to.
+    pub fn range(&self) -> Range<u64> {
+        self.address as u64..self.address as u64 + self.data.len() as u64
+    }
+}
+
+/// An image with all the data it contains.
+#[derive(Debug)]
+pub struct Image {
+    pub path: PathBuf,
+    pub format: ImageFormat,
+    /// The loadable data of the image, ordered by address.
+    pub segments: Vec<Segment>,
+}
+
+impl Image {
+    /// Reads the image at `path`.
+    ///
+    /// Raw binaries have no addresses, so they need a base address.
+    /// ELF and Intel HEX images contain their addresses, so they must not get one.
+    pub fn load(path: &Path, format: ImageFormat, base_address: Option<u32>) -> Result<Self> {
+        let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+
+        let mut segments = match (format, base_address) {
+            (ImageFormat::Bin, Some(address)) => Ok(vec![Segment {
+                address,
+                data: bytes,
+            }]),
+            (ImageFormat::Bin, None) => bail!(
+                "{} is a raw binary image without addresses, so it needs a base address.",
+                path.display()
+            ),
+            (_, Some(_)) => bail!(
+                "A base address can only be given for raw binary images, {} is a {:?} image which contains its addresses.",
+                path.display(),
+                format
+            ),
+            (ImageFormat::Elf, None) => parse_elf(&bytes),
+            (ImageFormat::Hex, None) => parse_hex(&String::from_utf8_lossy(&bytes)),
+        }
+        .with_context(|| format!("Failed to load {}", path.display()))?;
+        segments.sort_by_key(|segment| segment.address);
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            format,
+            segments,
+        })
+    }
+
+    /// Returns the number of bytes in the image.
+    pub fn size(&self) -> usize {
+        self.segments.iter().map(|segment| segment.data.len()).sum()
+    }
+}
+
+/// Returns the loadable segments of an ELF file, at their physical addresses.
+fn parse_elf(bytes: &[u8]) -> Result<Vec<Segment>> {
+    let elf = Elf::parse(bytes)?;
+
+    let mut segments = Vec::new();
+    for header in &elf.program_headers {
+        if header.p_type != PT_LOAD || header.p_filesz == 0 {
+            continue;
+        }
+        let data = bytes
+            .get(header.p_offset as usize..)
+            .and_then(|data| data.get(..header.p_filesz as usize))
+            .ok_or_else(|| anyhow!("A segment at {:#010x} is out of the file", header.p_paddr))?;
+        segments.push(Segment {
+            address: u32::try_from(header.p_paddr)?,
+            data: data.to_vec(),
+        });
+    }
+    Ok(segments)
+}
+
+/// Returns the data of an Intel HEX file, with consecutive records joined into one segment.
+fn parse_hex(text: &str) -> Result<Vec<Segment>> {
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut base = 0u32;
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let record =
+            parse_record(line).with_context(|| format!("Invalid record in line {}", number + 1))?;
+        let (address, kind, data) = (
+            u16::from_be_bytes([record[1], record[2]]) as u32,
+            record[3],
+            &record[4..record.len() - 1],
+        );
+
+        match kind {
+            // Data
+            0x00 => {
+                let address = base.wrapping_add(address);
+                match segments.last_mut() {
+                    Some(last) if last.range().end == address as u64 => {
+                        last.data.extend_from_slice(data)
+                    }
+                    _ => segments.push(Segment {
+                        address,
+                        data: data.to_vec(),
+                    }),
+                }
+            }
+            // End of file
+            0x01 => break,
+            // Extended segment address
+            0x02 if data.len() == 2 => base = u16::from_be_bytes([data[0], data[1]]) as u32 * 16,
+            // Extended linear address
+            0x04 if data.len() == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16,
+            // Start addresses do not contain data.
+            0x03 | 0x05 => {}
+            _ => bail!("Invalid record of type {:02x} in line {}", kind, number + 1),
+        }
+    }
+
+    Ok(segments)
+}
+
+/// Decodes a `:`-prefixed HEX record and checks its length and checksum.
+fn parse_record(line: &str) -> Result<Vec<u8>> {
+    let hex = line
+        .strip_prefix(':')
+        .ok_or_else(|| anyhow!("A record has to start with ':'"))?;
+    let record = (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| anyhow!("A record has to consist of hex bytes"))?;
+
+    if record.len() < 5 || record.len() != record[0] as usize + 5 {
+        bail!("The length of the record does not match its byte count");
+    }
+    if record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+        bail!("The checksum of the record is wrong");
+    }
+    Ok(record)
+}
+
+/// Returns an error if any two images contain data for the same address.
+pub fn check_overlaps(images: &[Image]) -> Result<()> {
+    for (i, first) in images.iter().enumerate() {
+        for second in &images[i + 1..] {
+            for a in &first.segments {
+                for b in &second.segments {
+                    let start = a.range().start.max(b.range().start);
+                    let end = a.range().end.min(b.range().end);
+                    if start < end {
+                        bail!(
+                            "The images {} and {} overlap at {:#010x}..{:#010x}",
+                            first.path.display(),
+                            second.path.display(),
+                            start,
+                            end
+                        );
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Flashes all images at once, so every sector is erased and programmed only once
+/// and the progress covers all images.
+///
+/// The images are joined into a temporary Intel HEX file, as probe-rs loads a single file.
+pub fn flash(
+    session: &mut Session,
+    images: &[Image],
+    keep_unwritten_bytes: bool,
+    progress: &FlashProgress,
+) -> Result<()> {
+    let path = std::env::temp_dir().join(format!("cargo-embed-{}.hex", std::process::id()));
+    fs::write(&path, to_hex(images))
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+
+    let result = download_file_with_options(
+        session,
+        &path,
+        Format::Hex,
+        DownloadOptions {
+            progress: Some(progress),
+            keep_unwritten_bytes,
+        },
+    );
+    if let Err(error) = fs::remove_file(&path) {
+        log::debug!("Failed to remove {}: {}", path.display(), error);
+    }
+    result?;
+    Ok(())
+}
+
+/// Returns the data of all images as Intel HEX records.
+fn to_hex(images: &[Image]) -> String {
+    let mut hex = String::new();
+    let mut upper = None;
+
+    for segment in images.iter().flat_map(|image| &image.segments) {
+        let mut address = segment.address;
+        let mut data = &segment.data[..];
+        while !data.is_empty() {
+            if upper != Some(address >> 16) {
+                upper = Some(address >> 16);
+                hex += &record(0, 0x04, &((address >> 16) as u16).to_be_bytes());
+            }
+            // A record must not cross a 64 KiB boundary, as its address only has 16 bits.
+            let size = data
+                .len()
+                .min(16)
+                .min(0x1_0000 - (address & 0xffff) as usize);
+            hex += &record(address as u16, 0x00, &data[..size]);
+            address = address.wrapping_add(size as u32);
+            data = &data[size..];
+        }
+    }
+
+    hex += &record(0, 0x01, &[]);
+    hex
+}
+
+/// Encodes a single Intel HEX record with its checksum.
+fn record(address: u16, kind: u8, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&address.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend_from_slice(data);
+    let checksum = bytes
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg();
+    bytes.push(checksum);
+
+    let mut line = String::from(":");
+    for byte in bytes {
+        line += &format!("{:02X}", byte);
+    }
+    line + "\n"
+}
+
+/// How many bytes are read back from the target at once.
+const VERIFY_CHUNK_SIZE: usize = 1024;
+
+/// How many mismatching bytes are reported when verifying fails.
+const REPORTED_MISMATCHES: usize = 16;
+
+/// A byte which was read back from the target with another value than in the image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Mismatch {
+    address: u32,
+    expected: u8,
+    actual: u8,
+}
+
+/// Reads back all images from the target and compares them with their contents.
+///
+/// `progress` is called with the number of bytes after every chunk which was read.
+/// If any byte differs, the error lists the first mismatching addresses.
+pub fn verify(
+    session: &mut Session,
+    images: &[Image],
+    mut progress: impl FnMut(u64),
+) -> Result<()> {
+    let mut mismatches = Vec::new();
+    let mut count = 0;
+
+    read_back(session, images, |address, expected, actual| {
+        count += compare(address, expected, actual, &mut mismatches);
+        progress(expected.len() as u64);
+    })?;
+
+    if count > 0 {
+        bail!(
+            "Verifying failed, {} bytes on the target differ from the images. The first ones are:\n{}",
+            count,
+            mismatches
+                .iter()
+                .map(|mismatch| format!(
+                    "    {:#010x}: expected {:02x}, read {:02x}\n",
+                    mismatch.address, mismatch.expected, mismatch.actual
+                ))
+                .collect::<String>()
+        );
+    }
+    Ok(())
+}
+
+/// Returns whether the target already contains all images, so flashing them can be skipped.
+///
+/// The loadable segments are read back and compared with the images.
+pub fn is_on_target(session: &mut Session, images: &[Image]) -> Result<bool> {
+    let mut differing = 0;
+    read_back(session, images, |_, expected, actual| {
+        differing += expected.iter().zip(actual).filter(|(a, b)| a != b).count();
+    })?;
+
+    log::debug!("{} bytes on the target differ from the images", differing);
+    Ok(differing == 0)
+}
+
+/// Reads back the data of all segments of the images in chunks.
+///
+/// `chunk` is called with the address, the image data and the data on the target of every chunk.
+fn read_back(
+    session: &mut Session,
+    images: &[Image],
+    chunk: impl FnMut(u32, &[u8], &[u8]),
+) -> Result<()> {
+    let mut core = session.core(0)?;
+    read_chunks(
+        images,
+        |address, data| Ok(core.read_8(address, data)?),
+        chunk,
+    )
+}
+
+/// Reads the data of all segments of the images in chunks with `read`
+/// and calls `chunk` with the address, the image data and the data read of every chunk.
+fn read_chunks(
+    images: &[Image],
+    mut read: impl FnMut(u32, &mut [u8]) -> Result<()>,
+    mut chunk: impl FnMut(u32, &[u8], &[u8]),
+) -> Result<()> {
+    for image in images {
+        for segment in &image.segments {
+            for (i, expected) in segment.data.chunks(VERIFY_CHUNK_SIZE).enumerate() {
+                let address = segment.address + (i * VERIFY_CHUNK_SIZE) as u32;
+                let mut actual = vec![0; expected.len()];
+                read(address, &mut actual)
+                    .with_context(|| format!("Failed to read back {:#010x}", address))?;
+
+                chunk(address, expected, &actual);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Adds the bytes which differ to `mismatches`, up to [`REPORTED_MISMATCHES`],
+/// and returns how many differ.
+fn compare(address: u32, expected: &[u8], actual: &[u8], mismatches: &mut Vec<Mismatch>) -> usize {
+    let mut count = 0;
+    for (offset, (expected, actual)) in expected.iter().zip(actual).enumerate() {
+        if expected != actual {
+            count += 1;
+            if mismatches.len() < REPORTED_MISMATCHES {
+                mismatches.push(Mismatch {
+                    address: address + offset as u32,
+                    expected: *expected,
+                    actual: *actual,
+                });
+            }
+        }
+    }
+    count
+}
+
+/// Parses an address given in hex with a `0x` prefix, or in decimal.
+pub fn parse_address(s: &str) -> Result<u32, String> {
+    let result = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(&hex.replace('_', ""), 16),
+        None => s.replace('_', "").parse(),
+    };
+    result.map_err(|_| format!("'{}' is not an address, use e.g. '0x8000000'", s))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        check_overlaps, compare, parse_address, parse_hex, read_chunks, to_hex, Image, ImageFormat,
+        Mismatch, Segment,
+    };
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn detects_formats() {
+        let dir = TempDir::new("detects-formats");
+        let detect =
+            |name: &str, contents: &[u8]| ImageFormat::detect(&dir.write(name, contents)).unwrap();
+
+        assert_eq!(detect("bootloader.hex", b""), ImageFormat::Hex);
+        assert_eq!(detect("app.BIN", b""), ImageFormat::Bin);
+        assert_eq!(detect("app", b"\x7fELF\x01\x01"), ImageFormat::Elf);
+        assert_eq!(
+            detect("bootloader.ihx", b":020000040800F2\n"),
+            ImageFormat::Hex
+        );
+        assert_eq!(detect("calibration.dat", b"\x00\x01"), ImageFormat::Bin);
+    }
+
+    #[test]
+    fn loads_binaries_at_their_base_address() {
+        let dir = TempDir::new("loads-binaries");
+        let calibration = dir.write("calibration.dat", b"\x00\x01");
+
+        assert!(Image::load(&calibration, ImageFormat::Bin, None).is_err());
+        assert!(Image::load(&calibration, ImageFormat::Hex, Some(0x0800_0000)).is_err());
+        let image = Image::load(&calibration, ImageFormat::Bin, Some(0x0800_0000)).unwrap();
+        assert_eq!(image.segments[0].range(), 0x0800_0000..0x0800_0002);
+    }
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!(parse_address("0x0800_0000"), Ok(0x0800_0000));
+        assert_eq!(parse_address("4096"), Ok(4096));
+        assert!(parse_address("0xfoo").is_err());
+    }
+
+    #[test]
+    fn parses_hex() {
+        let segments = parse_hex(
+            ":020000040800F2\n\
+             :0400000001020304F2\n\
+             :0400040005060708DE\n\
+             :02100000AABB89\n\
+             :00000001FF\n",
+        )
+        .unwrap();
+        assert_eq!(
+            segments,
+            [
+                Segment {
+                    address: 0x0800_0000,
+                    data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+                },
+                Segment {
+                    address: 0x0800_1000,
+                    data: vec![0xaa, 0xbb],
+                },
+            ]
+        );
+        assert!(parse_hex(":0400000001020304F3\n").is_err());
+    }
+
+    #[test]
+    fn finds_overlaps() {
+        let image = |path: &str, address: u32, size: usize| Image {
+            path: path.into(),
+            format: ImageFormat::Bin,
+            segments: vec![Segment {
+                address,
+                data: vec![0; size],
+            }],
+        };
+        let bootloader = image("mcuboot.hex", 0x0800_0000, 0x8000);
+        let application = image("app", 0x0800_8000, 0x1000);
+        let calibration = image("calibration.bin", 0x0800_8800, 0x100);
+
+        assert!(check_overlaps(&[bootloader, image("app", 0x0800_8000, 0x1000)]).is_ok());
+        assert_eq!(
+            check_overlaps(&[application, calibration])
+                .unwrap_err()
+                .to_string(),
+            "The images app and calibration.bin overlap at 0x08008800..0x08008900"
+        );
+    }
+
+    #[test]
+    fn writes_hex_which_parses_back() {
+        let segments = vec![
+            Segment {
+                address: 0x0800_fff8,
+                data: (0..40).collect(),
+            },
+            Segment {
+                address: 0x2000_0000,
+                data: vec![0xaa],
+            },
+        ];
+        let image = Image {
+            path: "app".into(),
+            format: ImageFormat::Elf,
+            segments: segments.clone(),
+        };
+
+        let hex = to_hex(&[image]);
+        assert!(hex.starts_with(":020000040800F2\n:08FFF800"));
+        assert!(hex.ends_with(":00000001FF\n"));
+        assert_eq!(parse_hex(&hex).unwrap(), segments);
+    }
+
+    #[test]
+    fn compares_read_back_data() {
+        let mut mismatches = Vec::new();
+        let expected = [0u8; 40];
+        let mut actual = [0u8; 40];
+        actual[3] = 0xff;
+        actual[20..40].iter_mut().for_each(|byte| *byte = 0x01);
+
+        assert_eq!(
+            compare(0x2000_0000, &expected, &expected, &mut mismatches),
+            0
+        );
+        assert_eq!(
+            compare(0x2000_0000, &expected, &actual, &mut mismatches),
+            21
+        );
+        assert_eq!(mismatches.len(), 16);
+        assert_eq!(
+            mismatches[0],
+            Mismatch {
+                address: 0x2000_0003,
+                expected: 0x00,
+                actual: 0xff,
+            }
+        );
+        assert_eq!(mismatches[1].address, 0x2000_0014);
+    }
+
+    #[test]
+    fn reads_back_segments_larger_than_a_chunk() {
+        let segment = Segment {
+            address: 0x0800_0000,
+            data: (0..3000).map(|i| i as u8).collect(),
+        };
+        let image = Image {
+            path: "app".into(),
+            format: ImageFormat::Elf,
+            segments: vec![segment.clone()],
+        };
+        let mut flash = segment.data.clone();
+        flash[2500] ^= 0xff;
+
+        let mut chunks = Vec::new();
+        let mut differing = 0;
+        read_chunks(
+            &[image],
+            |address, data| {
+                let offset = (address - segment.address) as usize;
+                data.copy_from_slice(&flash[offset..offset + data.len()]);
+                Ok(())
+            },
+            |address, expected, actual| {
+                chunks.push((address, expected.len()));
+                differing += compare(address, expected, actual, &mut Vec::new());
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            chunks,
+            [(0x0800_0000, 1024), (0x0800_0400, 1024), (0x0800_0800, 952)]
+        );
+        assert_eq!(differing, 1);
+    }
+}
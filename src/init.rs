@@ -0,0 +1,314 @@
+use anyhow::{anyhow, bail, Context, Result};
+use colored::*;
+use probe_rs::{config::TargetSelector, DebugProbeInfo, Probe};
+use probe_rs_cli_util::{build_artifact, logging};
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::Project,
+    probes::{self, Selection},
+    rttui,
+};
+
+/// Everything `cargo embed init` found out about the connected hardware and the firmware.
+#[derive(Debug, Default)]
+struct Findings {
+    /// The probe the generated config selects.
+    probe: Option<DebugProbeInfo>,
+    /// The chip the probe is connected to.
+    chip: Option<String>,
+    /// The ELF file which was checked for RTT and defmt.
+    elf: Option<PathBuf>,
+    /// Whether the ELF contains a `_SEGGER_RTT` control block.
+    rtt: bool,
+    /// Whether the ELF contains a defmt table.
+    defmt: bool,
+}
+
+/// Writes an `Embed.toml` for the connected probe and chip into the package directory.
+///
+/// The chip is detected by attaching to the target unless it was given with `--chip`.
+/// The firmware is built with `build_args` and checked for RTT and defmt support.
+pub fn run(
+    project: &Project,
+    work_dir: &Path,
+    build_args: &[String],
+    selection: Option<&Selection>,
+    chip: Option<&str>,
+    force: bool,
+) -> Result<()> {
+    let dir = match &project.package_dir {
+        Some(dir) => dir.clone(),
+        None => std::env::current_dir()?,
+    };
+    let path = dir.join("Embed.toml");
+    if path.exists() && !force {
+        bail!(
+            "{} already exists. Use '--force' to overwrite it.",
+            path.display()
+        );
+    }
+
+    let mut findings = Findings {
+        probe: find_probe(selection)?,
+        chip: chip.map(str::to_string),
+        ..Findings::default()
+    };
+
+    match &findings.probe {
+        Some(probe) => {
+            logging::println(format!(
+                "       {} {} ({:04x}:{:04x})",
+                "Probe".green().bold(),
+                probe.identifier,
+                probe.vendor_id,
+                probe.product_id
+            ));
+            if findings.chip.is_none() {
+                findings.chip = detect_chip(probe);
+            }
+        }
+        None => print_warning("No probe was found, the probe section is left commented out."),
+    }
+
+    match &findings.chip {
+        Some(chip) => logging::println(format!("        {} {}", "Chip".green().bold(), chip)),
+        None => print_warning(
+            "The chip could not be detected, set 'general.chip' in the generated file.",
+        ),
+    }
+
+    // A firmware which does not build yet should not prevent generating the config.
+    let elf = match build_artifact(work_dir, build_args) {
+        Ok(path) => Some(path),
+        Err(error) => {
+            print_warning(&format!(
+                "The firmware could not be built, so it is not checked for RTT and defmt: {}",
+                error
+            ));
+            None
+        }
+    };
+
+    if let Some(elf) = &elf {
+        let mut file =
+            File::open(elf).with_context(|| format!("Failed to open {}", elf.display()))?;
+        findings.rtt = rttui::app::App::get_rtt_symbol(&mut file).is_some();
+
+        let bytes = fs::read(elf).with_context(|| format!("Failed to read {}", elf.display()))?;
+        findings.defmt = matches!(
+            defmt_elf2table::parse(&bytes),
+            Ok(Some(table)) if !table.is_empty()
+        );
+
+        logging::println(format!(
+            "    {} {} (RTT: {}, defmt: {})",
+            "Firmware".green().bold(),
+            elf.display(),
+            yes_no(findings.rtt),
+            yes_no(findings.defmt)
+        ));
+    }
+    findings.elf = elf;
+
+    fs::write(&path, findings.render())
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+
+    logging::println(format!(
+        "     {} {}",
+        "Created".green().bold(),
+        path.display()
+    ));
+
+    Ok(())
+}
+
+/// Returns the probe which matches `selection`, or the only connected probe.
+///
+/// Without a selection, several connected probes are an error, as there is no way to tell
+/// which one should be written to the config.
+fn find_probe(selection: Option<&Selection>) -> Result<Option<DebugProbeInfo>> {
+    let list = Probe::list_all();
+
+    if let Some(selection) = selection {
+        return list
+            .into_iter()
+            .find(|info| selection.matches(info))
+            .map(Some)
+            .ok_or_else(|| anyhow!("The probe {} is not connected", selection));
+    }
+
+    if list.len() > 1 {
+        bail!(
+            "The following devices were found:\n{}Use '--probe VID:PID' to select the probe the config is generated for.",
+            list.iter()
+                .enumerate()
+                .map(|(num, link)| format!("[{}]: {:?}\n", num, link))
+                .collect::<String>()
+        );
+    }
+
+    Ok(list.into_iter().next())
+}
+
+/// Attaches to the target with the given probe and returns the name of the detected chip.
+fn detect_chip(info: &DebugProbeInfo) -> Option<String> {
+    let result = Probe::open(info)
+        .map_err(anyhow::Error::from)
+        .and_then(|probe| Ok(probe.attach(TargetSelector::Auto)?));
+
+    match result {
+        Ok(session) => probes::chip_name(&session),
+        Err(error) => {
+            log::info!("Failed to detect the chip: {:?}", error);
+            None
+        }
+    }
+}
+
+impl Findings {
+    /// Renders the findings as a commented `Embed.toml` with a `default`, `rtt` and `gdb` config.
+    fn render(&self) -> String {
+        let chip = match &self.chip {
+            Some(chip) => format!("chip = \"{}\"\n", chip),
+            None => "# The chip could not be detected, `cargo embed --list-chips` lists all known chips.\n\
+                     # chip = \"name\"\n"
+                .to_string(),
+        };
+
+        let probe = match &self.probe {
+            Some(probe) => format!(
+                "# The {} ({:?}) which was connected when this file was generated.\n\
+                 usb_vid = \"{:04x}\"\n\
+                 usb_pid = \"{:04x}\"\n\
+                 # Uncomment to only use this exact probe if several probes of this type are connected.\n\
+                 # serial = \"{}\"\n",
+                probe.identifier,
+                probe.probe_type,
+                probe.vendor_id,
+                probe.product_id,
+                probe.serial_number.as_deref().unwrap_or("12345678")
+            ),
+            None => "# No probe was connected when this file was generated.\n\
+                     # usb_vid = \"1337\"\n\
+                     # usb_pid = \"1337\"\n"
+                .to_string(),
+        };
+
+        let mut rtt = String::new();
+        if let (Some(elf), false) = (&self.elf, self.rtt) {
+            rtt += &format!(
+                "# No `_SEGGER_RTT` symbol was found in {}. Does your firmware run RTT?\n",
+                elf.display()
+            );
+        }
+        rtt += "enabled = true\n";
+        rtt += if self.defmt {
+            "# The firmware uses defmt, so its log messages are decoded on the host.\n\
+             channels = [\n    { up = 0, name = \"defmt\", format = \"Defmt\" },\n]\n"
+        } else {
+            "# channels = [\n#     { up = 0, down = 0, name = \"Terminal\", format = \"String\" },\n# ]\n"
+        };
+
+        format!(
+            r#"# Generated by `cargo embed init`.
+# All options are documented in https://github.com/probe-rs/cargo-embed/blob/master/src/config/default.toml
+#
+# Run `cargo embed` to flash the firmware,
+# `cargo embed rtt` to open an RTT terminal after flashing and
+# `cargo embed gdb` to start a GDB server after flashing.
+
+[default.general]
+# The chip name of the chip to be debugged.
+{chip}
+[default.probe]
+{probe}# The protocol to be used for communicating with the target.
+protocol = "Swd"
+
+[default.flashing]
+enabled = true
+
+[default.reset]
+enabled = true
+halt_afterwards = false
+
+[default.rtt]
+enabled = false
+
+[default.gdb]
+enabled = false
+
+[rtt.rtt]
+{rtt}
+[gdb.reset]
+# Halt the core so the debugger can take over.
+halt_afterwards = true
+
+[gdb.gdb]
+enabled = true
+gdb_connection_string = "127.0.0.1:1337"
+"#,
+            chip = chip,
+            probe = probe,
+            rtt = rtt
+        )
+    }
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+fn print_warning(warning: &str) {
+    logging::eprintln(format!("     {} {}", "Warning".yellow().bold(), warning));
+}
+
+#[cfg(test)]
+mod test {
+    use super::Findings;
+    use probe_rs::{DebugProbeInfo, DebugProbeType};
+
+    #[test]
+    fn renders_valid_toml() {
+        let findings = Findings {
+            probe: Some(DebugProbeInfo {
+                identifier: "J-Link".to_string(),
+                vendor_id: 0x1366,
+                product_id: 0x1015,
+                serial_number: Some("000683000000".to_string()),
+                probe_type: DebugProbeType::JLink,
+            }),
+            chip: Some("nRF52840_xxAA".to_string()),
+            elf: Some("target/thumbv7em-none-eabihf/debug/app".into()),
+            rtt: true,
+            defmt: true,
+        };
+
+        let rendered: toml::Value = toml::from_str(&findings.render()).unwrap();
+        assert_eq!(
+            rendered["default"]["general"]["chip"].as_str(),
+            Some("nRF52840_xxAA")
+        );
+        assert_eq!(
+            rendered["default"]["probe"]["usb_vid"].as_str(),
+            Some("1366")
+        );
+        assert_eq!(rendered["default"]["probe"].get("serial"), None);
+        assert_eq!(
+            rendered["rtt"]["rtt"]["channels"][0]["format"].as_str(),
+            Some("Defmt")
+        );
+        assert_eq!(rendered["gdb"]["gdb"]["enabled"].as_bool(), Some(true));
+
+        let empty: toml::Value = toml::from_str(&Findings::default().render()).unwrap();
+        assert_eq!(empty["default"]["general"].get("chip"), None);
+        assert_eq!(empty["default"]["probe"].get("usb_vid"), None);
+    }
+}
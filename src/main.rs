@@ -1,12 +1,25 @@
+mod cache;
 mod config;
+mod doctor;
 mod error;
+mod hooks;
+mod image;
+mod init;
+mod patch;
+mod plan;
+mod probes;
+mod report;
 mod rttui;
+mod speed;
+#[cfg(test)]
+mod test_util;
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use chrono::Local;
 use colored::*;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use std::{
+    collections::BTreeMap,
     env, fs,
     fs::File,
     io::Write,
@@ -20,8 +33,8 @@ use structopt::StructOpt;
 
 use probe_rs::{
     config::TargetSelector,
-    flashing::{download_file_with_options, DownloadOptions, FlashProgress, Format, ProgressEvent},
-    DebugProbeSelector, Probe,
+    flashing::{FlashProgress, ProgressEvent},
+    DebugProbeInfo, Probe,
 };
 #[cfg(feature = "sentry")]
 use probe_rs_cli_util::logging::{ask_to_log_crash, capture_anyhow, capture_panic};
@@ -55,13 +68,82 @@ struct Opt {
     #[structopt(
         long = "probe",
         help = "Use this flag to select a specific probe in the list.\n\
-        Use '--probe VID:PID' or '--probe VID:PID:Serial' if you have more than one probe with the same VID:PID."
+        Use '--probe VID:PID' or '--probe VID:PID:Serial' if you have more than one probe with the same VID:PID.\n\
+        Use '--probe <alias>' to select a probe defined in the [probes] table of the config."
     )]
-    probe_selector: Option<DebugProbeSelector>,
+    probe_selector: Option<String>,
+    #[structopt(
+        long = "set",
+        number_of_values = 1,
+        help = "Override a config key, e.g. '--set rtt.enabled=true'. Can be given multiple times.\n\
+        Takes precedence over the config files and CARGO_EMBED_<SECTION>_<KEY> environment variables."
+    )]
+    set: Vec<config::Override>,
+    #[structopt(
+        long = "wait",
+        value_name = "ms",
+        help = "Wait up to this many milliseconds for the probe and the target to become available.\n\
+        Overrides 'probe.wait_timeout'."
+    )]
+    wait: Option<usize>,
     #[structopt(name = "list-chips", long = "list-chips")]
     list_chips: bool,
+    #[structopt(
+        name = "list-configs",
+        long = "list-configs",
+        help = "List all configs with the files defining them, what they derive from and their most important values."
+    )]
+    list_configs: bool,
     #[structopt(name = "disable-progressbars", long = "disable-progressbars")]
     disable_progressbars: bool,
+    #[structopt(
+        long = "verify",
+        help = "Read back the flash after programming and compare it with the images.\n\
+        Same as 'flashing.verify = true'."
+    )]
+    verify: bool,
+    #[structopt(
+        long = "dry-run",
+        help = "Show which sectors would be erased and which pages programmed, without attaching to the target."
+    )]
+    dry_run: bool,
+    #[structopt(
+        long = "dry-run-format",
+        possible_values = &["text", "json"],
+        default_value = "text",
+        help = "Print the plan of '--dry-run' as a table or as JSON."
+    )]
+    dry_run_format: plan::OutputFormat,
+    #[structopt(
+        long = "report",
+        parse(from_os_str),
+        help = "Write a JSON report with the timeline and throughput of flashing to this path."
+    )]
+    report: Option<PathBuf>,
+    #[structopt(
+        long = "image",
+        parse(from_os_str),
+        help = "Flash this image instead of building the firmware with cargo."
+    )]
+    image: Option<PathBuf>,
+    #[structopt(
+        long = "format",
+        possible_values = &["elf", "hex", "bin"],
+        help = "The format of the '--image'. Detected from the file if not given."
+    )]
+    format: Option<image::ImageFormat>,
+    #[structopt(
+        long = "base-address",
+        parse(try_from_str = image::parse_address),
+        help = "The address a raw binary '--image' is flashed to, e.g. '0x8000000'."
+    )]
+    base_address: Option<u32>,
+    #[structopt(
+        long = "elf",
+        parse(from_os_str),
+        help = "The ELF file with the symbols of the '--image', used for RTT and defmt."
+    )]
+    elf: Option<PathBuf>,
 
     // `cargo build` arguments
     #[structopt(name = "binary", long = "bin")]
@@ -82,11 +164,172 @@ struct Opt {
     all_features: bool,
     #[structopt(long)]
     features: Vec<String>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Inspect the cargo-embed configuration.
+    Config(ConfigCommand),
+    /// List all connected probes.
+    Probes,
+    /// Check step by step why flashing or debugging does not work.
+    ///
+    /// Opens the probe, selects the protocol, sets the speed, attaches to the target without and
+    /// under reset, looks for the RTT symbol in the firmware and checks the GDB port, printing a
+    /// hint for every step which fails.
+    Doctor,
+    /// Find the fastest speed at which the probe reliably talks to the target.
+    ///
+    /// Steps through increasing speeds and writes and reads back target RAM repeatedly at each.
+    /// The result is cached for the probe if 'probe.cache_speed' is enabled,
+    /// and used by 'speed = "auto"'.
+    ProbeSpeed,
+    /// Generate an Embed.toml for the connected probe and chip.
+    ///
+    /// The firmware is built with the given cargo arguments and checked for RTT and defmt support.
+    Init {
+        #[structopt(long = "force", help = "Overwrite an existing Embed.toml.")]
+        force: bool,
+    },
+}
+
+impl Command {
+    /// Returns the name the subcommand is given with.
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Config(_) => "config",
+            Command::Probes => "probes",
+            Command::Doctor => "doctor",
+            Command::ProbeSpeed => "probe-speed",
+            Command::Init { .. } => "init",
+        }
+    }
 }
 
-const ARGUMENTS_TO_REMOVE: &[&str] = &["list-chips", "disable-progressbars", "chip=", "probe="];
+#[derive(Debug, StructOpt)]
+enum ConfigCommand {
+    /// Print the fully resolved config.
+    Show {
+        /// The config to show. Defaults to the config given to cargo-embed itself.
+        #[structopt(name = "config")]
+        config: Option<String>,
+        #[structopt(
+            long = "format",
+            default_value = "toml",
+            possible_values = &["toml", "json"],
+            help = "The format the config is printed in."
+        )]
+        format: String,
+        #[structopt(
+            long = "annotate",
+            help = "Print the file and config which set each value next to it."
+        )]
+        annotate: bool,
+    },
+    /// Print a JSON Schema of the config files.
+    ///
+    /// Editors like VS Code (with Even Better TOML) can use it to validate and complete Embed.toml.
+    Schema,
+    /// Move deprecated keys in the config files to their new place.
+    ///
+    /// Comments and formatting are kept. A diff is shown before any file is written.
+    Migrate {
+        #[structopt(
+            long = "yes",
+            help = "Write the changes without asking for confirmation."
+        )]
+        yes: bool,
+    },
+}
+
+impl Opt {
+    /// Returns the arguments for `cargo build` which were given to cargo-embed.
+    ///
+    /// This is used by subcommands, whose own arguments `cargo build` does not understand.
+    fn cargo_build_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for (flag, value) in &[
+            ("--bin", &self.bin),
+            ("--example", &self.example),
+            ("--package", &self.package),
+            ("--target", &self.target),
+        ] {
+            if let Some(value) = value {
+                args.push(flag.to_string());
+                args.push(value.clone());
+            }
+        }
+        if let Some(manifest_path) = &self.manifest_path {
+            args.push("--manifest-path".to_string());
+            args.push(manifest_path.display().to_string());
+        }
+        for (flag, enabled) in &[
+            ("--release", self.release),
+            ("--no-default-features", self.no_default_features),
+            ("--all-features", self.all_features),
+        ] {
+            if *enabled {
+                args.push(flag.to_string());
+            }
+        }
+        for features in &self.features {
+            args.push("--features".to_string());
+            args.push(features.clone());
+        }
+        args
+    }
+
+    /// Returns the values of the variables which can be used in the config.
+    fn variables(&self, config_name: &str, project: &config::Project) -> config::Variables {
+        // Without '--bin' or '--example', cargo builds the only binary of the package.
+        let binary = match project.binaries.as_slice() {
+            [binary] => Some(binary.clone()),
+            _ => None,
+        };
+
+        config::Variables {
+            workspace_root: project.workspace_root.clone(),
+            package_dir: project.package_dir.clone(),
+            profile: config_name.to_string(),
+            chip: self.chip.clone(),
+            binary: self
+                .image
+                .as_ref()
+                .and_then(|image| image.file_stem())
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .or_else(|| self.bin.clone())
+                .or_else(|| self.example.clone())
+                .or(binary),
+        }
+    }
+}
+
+const ARGUMENTS_TO_REMOVE: &[&str] = &[
+    "list-chips",
+    "list-configs",
+    "disable-progressbars",
+    "verify",
+    "dry-run",
+    "dry-run-format=",
+    "report=",
+    "chip=",
+    "probe=",
+    "wait=",
+    "image=",
+    "format=",
+    "base-address=",
+    "elf=",
+    "set=",
+];
 
 fn main() {
+    // The hooks are set once the config is loaded. From then on, `on_exit` runs once however
+    // cargo-embed exits, also after errors, panics and Ctrl-C.
+    let hooks = hooks::Runner::default();
+
     let next = panic::take_hook();
     panic::set_hook(Box::new(move |info| {
         #[cfg(feature = "sentry")]
@@ -98,37 +341,44 @@ fn main() {
         next(info);
     }));
 
-    match main_try() {
-        Ok(_) => (),
-        Err(e) => {
-            // Ensure stderr is flushed before calling proces::exit,
-            // otherwise the process might panic, because it tries
-            // to access stderr during shutdown.
-            //
-            // We ignore the errors, not much we can do anyway.
-
-            let mut stderr = std::io::stderr();
-
-            let first_line_prefix = "Error".red().bold();
-            let other_line_prefix: String = iter::repeat(" ")
-                .take(first_line_prefix.chars().count())
-                .collect();
-
-            let error = format!("{:?}", e);
-
-            for (i, line) in error.lines().enumerate() {
-                let _ = write!(stderr, "       ");
-
-                if i == 0 {
-                    let _ = write!(stderr, "{}", first_line_prefix);
-                } else {
-                    let _ = write!(stderr, "{}", other_line_prefix);
-                };
-
-                let _ = writeln!(stderr, " {}", line);
+    // RTT mode gets Ctrl-C as a key and returns from `main_try`, otherwise, e.g. in GDB mode,
+    // it interrupts cargo-embed.
+    // If the handler cannot be set, Ctrl-C ends cargo-embed without the `on_exit` hooks.
+    let interrupted = hooks.clone();
+    let _ = ctrlc::set_handler(move || {
+        if let Err(err) = interrupted.run_on_exit() {
+            print_error(&err);
+            process::exit(1);
+        }
+        process::exit(130);
+    });
+
+    // Panics in other threads, e.g. the GDB server, don't end cargo-embed, so `on_exit` is only
+    // run here once `main_try` returned or unwound.
+    let result = match panic::catch_unwind(panic::AssertUnwindSafe(|| main_try(&hooks))) {
+        Ok(result) => result,
+        Err(panic) => {
+            // The panic itself was reported by the panic hook.
+            if let Err(err) = hooks.run_on_exit() {
+                print_error(&err);
             }
+            panic::resume_unwind(panic);
+        }
+    };
+
+    // The `on_exit` hooks run after errors as well, which are reported first.
+    let result = match (result, hooks.run_on_exit()) {
+        (Err(err), Err(on_exit)) => {
+            log::error!("{:#}", on_exit);
+            Err(err)
+        }
+        (result, on_exit) => result.and(on_exit),
+    };
 
-            let _ = stderr.flush();
+    match result {
+        Ok(_) => (),
+        Err(e) => {
+            print_error(&e);
 
             #[cfg(feature = "sentry")]
             if ask_to_log_crash() {
@@ -142,7 +392,39 @@ fn main() {
     }
 }
 
-fn main_try() -> Result<()> {
+/// Prints the error with its causes to stderr.
+fn print_error(e: &anyhow::Error) {
+    // Ensure stderr is flushed before calling proces::exit,
+    // otherwise the process might panic, because it tries
+    // to access stderr during shutdown.
+    //
+    // We ignore the errors, not much we can do anyway.
+
+    let mut stderr = std::io::stderr();
+
+    let first_line_prefix = "Error".red().bold();
+    let other_line_prefix: String = iter::repeat(" ")
+        .take(first_line_prefix.chars().count())
+        .collect();
+
+    let error = format!("{:?}", e);
+
+    for (i, line) in error.lines().enumerate() {
+        let _ = write!(stderr, "       ");
+
+        if i == 0 {
+            let _ = write!(stderr, "{}", first_line_prefix);
+        } else {
+            let _ = write!(stderr, "{}", other_line_prefix);
+        };
+
+        let _ = writeln!(stderr, " {}", line);
+    }
+
+    let _ = stderr.flush();
+}
+
+fn main_try(hooks: &hooks::Runner) -> Result<()> {
     let mut args = std::env::args();
 
     // When called by Cargo, the first argument after the binary name will be `embed`. If that's the
@@ -168,41 +450,145 @@ fn main_try() -> Result<()> {
 
     // Get the config.
     let config_name = opt.config.as_deref().unwrap_or("default");
-    let config = config::Configs::try_new(config_name)
+
+    // Environment variables are applied first, so `--set` takes precedence over them.
+    let (mut overrides, env_warnings) = config::Override::from_env();
+    print_config_warnings(&env_warnings);
+    overrides.extend(opt.set.iter().cloned());
+
+    let project = config::Project::discover(opt.manifest_path.as_deref(), opt.package.as_deref())?;
+
+    if opt.list_configs {
+        let configs = config::Configs::load(&project)?;
+        print_config_warnings(&configs.warnings);
+        print!("{}", configs.to_list(&overrides));
+        return Ok(());
+    }
+
+    if let Some(command) = &opt.command {
+        // Subcommands are parsed before the config name, so a config named like one is never selected.
+        if let Ok(configs) = config::Configs::load(&project) {
+            if configs.names().iter().any(|name| name == command.name()) {
+                print_config_warnings(&[format!(
+                    "Running the '{0}' command. The config '{0}' cannot be selected \
+                     as it has the name of a command, rename it.",
+                    command.name()
+                )]);
+            }
+        }
+        return run_command(&opt, command, &work_dir, &project, &overrides);
+    }
+
+    let mut resolved = config::Configs::try_new(config_name, &project, &overrides)
         .with_context(|| format!("The config '{}' could not be loaded.", config_name))?;
+    resolved.interpolate(&opt.variables(config_name, &project))?;
+    let config = resolved.config;
+    hooks.init(&config.general.hooks, config_name);
 
     logging::init(Some(config.general.log_level));
 
-    // Make sure we load the config given in the cli parameters.
-    for cdp in &config.general.chip_descriptions {
-        probe_rs::config::add_target_from_yaml(&Path::new(cdp))
-            .with_context(|| format!("failed to load the chip description from {}", cdp))?;
+    print_config_warnings(&resolved.warnings);
+
+    log::info!("Merged configs: {}", resolved.chain.join(" -> "));
+    for (key, origin) in &resolved.origins {
+        log::debug!(
+            "Config key '{}' was set by config '{}' in {}",
+            key,
+            origin.config,
+            origin.source
+        );
     }
 
-    let chip = if opt.list_chips {
-        print_families()?;
-        std::process::exit(0);
-    } else {
-        opt.chip
-            .or_else(|| config.general.chip.clone())
-            .map(|chip| chip.into())
-            .unwrap_or(TargetSelector::Auto)
-    };
+    // Make sure we load the config given in the cli parameters.
+    let (chip_name, chip) = chip(&opt, &config)?;
+    if opt.list_chips {
+        return print_families();
+    }
 
     METADATA.lock().unwrap().chip = Some(format!("{:?}", chip));
 
-    // Remove executable name from the arguments list.
-    args.remove(0);
+    if let Some(chip_name) = &chip_name {
+        hooks.set_env("EMBED_CHIP", chip_name);
+    }
+
+    // A prebuilt image is flashed as it is, its symbols can only come from '--elf'.
+    let (path, format, elf) = match &opt.image {
+        Some(image) => {
+            let image_format = match opt.format {
+                Some(format) => format,
+                None => image::ImageFormat::detect(image)?,
+            };
+            let elf = match (&opt.elf, image_format) {
+                (Some(elf), _) => Some(elf.clone()),
+                (None, image::ImageFormat::Elf) => Some(image.clone()),
+                (None, _) => None,
+            };
+            (image.clone(), image_format, elf)
+        }
+        None => {
+            if opt.format.is_some() || opt.base_address.is_some() {
+                bail!("'--format' and '--base-address' can only be used together with '--image'.");
+            }
+
+            // Remove executable name from the arguments list.
+            args.remove(0);
+
+            // Remove all arguments that `cargo build` does not understand.
+            argument_handling::remove_arguments(ARGUMENTS_TO_REMOVE, &mut args);
+
+            if let Some(index) = args.iter().position(|x| x == config_name) {
+                // We remove the argument we found.
+                args.remove(index);
+            }
 
-    // Remove all arguments that `cargo build` does not understand.
-    argument_handling::remove_arguments(ARGUMENTS_TO_REMOVE, &mut args);
+            let path = build_artifact(&work_dir, &args)?;
+            (
+                path.clone(),
+                image::ImageFormat::Elf,
+                Some(opt.elf.clone().unwrap_or(path)),
+            )
+        }
+    };
 
-    if let Some(index) = args.iter().position(|x| x == config_name) {
-        // We remove the argument we found.
-        args.remove(index);
+    hooks.set_env("EMBED_IMAGE", path.display().to_string());
+    if let Some(elf) = &elf {
+        hooks.set_env("EMBED_ELF", elf.display().to_string());
     }
 
-    let path = build_artifact(&work_dir, &args)?;
+    // All images are read before attaching, so overlapping images are reported
+    // before anything is erased.
+    let mut images = vec![image::Image::load(&path, format, opt.base_address)?];
+    for image_config in &config.flashing.images {
+        let image_path = PathBuf::from(&image_config.path);
+        let image_format = match image_config.format {
+            Some(format) => format,
+            None => image::ImageFormat::detect(&image_path)?,
+        };
+        images.push(
+            image::Image::load(&image_path, image_format, image_config.offset)
+                .context("Failed to load an image from 'flashing.images'")?,
+        );
+    }
+    image::check_overlaps(&images)?;
+
+    if opt.dry_run {
+        let layouts = plan::LayoutCache::load(&project);
+        let rates = plan::RateCache::load(&project);
+        let plan = plan::Plan::build(
+            &plan::target(&chip)?,
+            &images,
+            config.flashing.restore_unwritten_bytes,
+            layouts
+                .as_ref()
+                .zip(chip_name.as_deref())
+                .and_then(|(layouts, chip_name)| layouts.get(chip_name)),
+            rates
+                .as_ref()
+                .zip(chip_name.as_deref())
+                .and_then(|(rates, chip_name)| rates.get(chip_name)),
+        )?;
+        return plan.print(opt.dry_run_format);
+    }
 
     // Get the binary name (without extension) from the build artifact path
     let name = path.file_stem().and_then(|f| f.to_str()).ok_or_else(|| {
@@ -218,102 +604,176 @@ fn main_try() -> Result<()> {
         "Target".green().bold(),
         path.display()
     ));
+    for image in &images[1..] {
+        logging::println(format!(
+            "       {} {} ({:?}, {} bytes)",
+            "Image".green().bold(),
+            image.path.display(),
+            image.format,
+            image.size()
+        ));
+    }
+
+    // Waiting for the probe and the target shares one deadline.
+    let wait_timeout = opt.wait.unwrap_or(config.probe.wait_timeout);
+    let deadline = Instant::now() + Duration::from_millis(wait_timeout as u64);
 
     // If we got a probe selector in the config, open the probe matching the selector if possible.
-    let mut probe = if let Some(selector) = opt.probe_selector {
-        Probe::open(selector)?
-    } else {
-        match (config.probe.usb_vid.as_ref(), config.probe.usb_pid.as_ref()) {
-            (Some(vid), Some(pid)) => {
-                let selector = DebugProbeSelector {
-                    vendor_id: u16::from_str_radix(vid, 16)?,
-                    product_id: u16::from_str_radix(pid, 16)?,
-                    serial_number: config.probe.serial.clone(),
-                };
-                // if two probes with the same VID:PID pair exist we just choose one
-                Probe::open(selector)?
-            }
-            _ => {
-                if config.probe.usb_vid.is_some() {
-                    log::warn!("USB VID ignored, because PID is not specified.");
-                }
-                if config.probe.usb_pid.is_some() {
-                    log::warn!("USB PID ignored, because VID is not specified.");
+    let selection = match selection(&opt, &config, &resolved.probes)? {
+        Some(selection) => {
+            probes::wait_until(deadline, !opt.disable_progressbars, || {
+                if Probe::list_all().iter().any(|info| selection.matches(info)) {
+                    Ok(())
+                } else {
+                    Err(anyhow!("The probe {} is not connected", selection))
                 }
-
-                // Only automatically select a probe if there is only
-                // a single probe detected.
+            })?;
+            selection
+        }
+        None => {
+            let list = probes::wait_until(deadline, !opt.disable_progressbars, || {
                 let list = Probe::list_all();
-                if list.len() > 1 {
-                    return Err(anyhow!("The following devices were found:\n \
-                                    {} \
-                                        \
-                                    Use '--probe VID:PID'\n \
-                                                            \
-                                    You can also set the [default.probe] config attribute \
-                                    (in your Embed.toml) to select which probe to use. \
-                                    For usage examples see https://github.com/probe-rs/cargo-embed/blob/master/src/config/default.toml .",
-                                    list.iter().enumerate().map(|(num, link)| format!("[{}]: {:?}\n", num, link)).collect::<String>()));
+                if list.is_empty() {
+                    Err(anyhow!("No supported probe was found"))
+                } else {
+                    Ok(list)
                 }
-                Probe::open(
-                    list.first()
-                        .map(|info| {
-                            METADATA.lock().unwrap().probe = Some(format!("{:?}", info.probe_type));
-                            info
-                        })
-                        .ok_or_else(|| anyhow!("No supported probe was found"))?,
-                )?
-            }
+            })?;
+
+            let info = select_connected(&list, &resolved.probes, &project)?;
+            METADATA.lock().unwrap().probe = Some(format!("{:?}", info.probe_type));
+            probes::Selection::from(info)
         }
     };
 
-    probe
-        .select_protocol(config.probe.protocol)
-        .context("failed to select protocol")?;
+    hooks.set_env("EMBED_PROBE", selection.to_string());
+    let serial = selection.selector.serial_number.clone().or_else(|| {
+        Probe::list_all()
+            .into_iter()
+            .find(|info| selection.matches(info))
+            .and_then(|info| info.serial_number)
+    });
+    if let Some(serial) = serial {
+        hooks.set_env("EMBED_PROBE_SERIAL", serial);
+    }
 
-    let protocol_speed = if let Some(speed) = config.probe.speed {
-        let actual_speed = probe.set_speed(speed).context("failed to set speed")?;
+    // The values of a unit are patched in once its probe is known, so commands can use it.
+    let patches = patch::prepare(&config.flashing.patch, elf.as_deref(), hooks)?;
+    patch::apply(&mut images, &patches)?;
 
-        if actual_speed < speed {
-            log::warn!(
-                "Unable to use specified speed of {} kHz, actual speed used is {} kHz",
-                speed,
-                actual_speed
-            );
-        }
+    hooks.run(hooks::Hook::PreAttach)?;
 
-        actual_speed
-    } else {
-        probe.speed_khz()
-    };
+    // Attaching consumes the probe, so it is opened again for every attempt.
+    let attach = |speed: Option<u32>| -> Result<_> {
+        // If two probes with the same VID:PID pair exist we just choose one.
+        let mut probe = selection.open()?;
 
-    METADATA.lock().unwrap().speed = Some(format!("{:?}", protocol_speed));
+        probe
+            .select_protocol(config.probe.protocol)
+            .context("failed to select protocol")?;
 
-    log::info!("Protocol speed {} kHz", protocol_speed);
+        let protocol_speed = if let Some(speed) = speed {
+            let actual_speed = probe.set_speed(speed).context("failed to set speed")?;
 
-    let mut session = if config.general.connect_under_reset {
-        probe
-            .attach_under_reset(chip)
-            .context("failed attaching to target")?
-    } else {
-        let potential_session = probe.attach(chip);
-        match potential_session {
-            Ok(session) => session,
-            Err(err) => {
-                log::info!("The target seems to be unable to be attached to.");
-                log::info!(
-                    "A hard reset during attaching might help. This will reset the entire chip."
+            if actual_speed < speed {
+                log::warn!(
+                    "Unable to use specified speed of {} kHz, actual speed used is {} kHz",
+                    speed,
+                    actual_speed
                 );
-                log::info!("Set `general.connect_under_reset` in your cargo-embed configuration file to enable this feature.");
-                return Err(err).context("failed attaching to target");
             }
-        }
+
+            actual_speed
+        } else {
+            probe.speed_khz()
+        };
+
+        METADATA.lock().unwrap().speed = Some(format!("{:?}", protocol_speed));
+
+        log::info!("Protocol speed {} kHz", protocol_speed);
+
+        let probe_name = probe.get_name();
+        let session = if config.general.connect_under_reset {
+            probe
+                .attach_under_reset(chip.clone())
+                .context("failed attaching to target")
+        } else {
+            let potential_session = probe.attach(chip.clone());
+            match potential_session {
+                Ok(session) => Ok(session),
+                Err(err) => {
+                    log::info!("The target seems to be unable to be attached to.");
+                    log::info!(
+                        "A hard reset during attaching might help. This will reset the entire chip."
+                    );
+                    log::info!("Set `general.connect_under_reset` in your cargo-embed configuration file to enable this feature.");
+                    Err(err).context("failed attaching to target")
+                }
+            }
+        }?;
+        Ok((session, probe_name, protocol_speed))
     };
+    let speed = match config.probe.speed {
+        Some(config::Speed::Khz(speed)) => Some(speed),
+        Some(config::Speed::Auto) | None => None,
+    };
+    let mut attached = probes::wait_until(deadline, !opt.disable_progressbars, || attach(speed))?;
+    if config.probe.speed == Some(config::Speed::Auto) {
+        // The speed is measured once the probe and the target are ready, which the attempts
+        // above waited for. Measuring attaches on its own, so the session is closed first.
+        drop(attached);
+        let speed = speed::auto(
+            &selection,
+            &config,
+            &chip,
+            chip_name.as_deref().unwrap_or("auto"),
+            &project,
+        )?;
+        attached = attach(Some(speed))?;
+    }
+    let (mut session, probe_name, protocol_speed) = attached;
+    if chip_name.is_none() {
+        if let Some(detected) = probes::chip_name(&session) {
+            hooks.set_env("EMBED_CHIP", detected);
+        }
+    }
 
-    if config.flashing.enabled {
-        // Start timer.
-        let instant = Instant::now();
+    // Comparing the flash contents is much faster than erasing and programming it again.
+    let up_to_date = config.flashing.enabled
+        && config.flashing.skip_if_unchanged
+        && image::is_on_target(&mut session, &images).unwrap_or_else(|err| {
+            log::warn!(
+                "Failed to compare the flash contents with the images: {:#}",
+                err
+            );
+            false
+        });
+    if up_to_date {
+        logging::println(format!(
+            "  {} {}{}",
+            "Up to date".green().bold(),
+            path.display(),
+            if patches.is_empty() {
+                ""
+            } else {
+                ", including the patched values, nothing was written"
+            }
+        ));
+    }
+
+    // The layout is shown after flashing, the rates are used to estimate the time of dry runs
+    // and the timeline goes into the report.
+    let recorder = Arc::new(report::Recorder::default());
+    let report_started = Local::now();
+    let verify = opt.verify || config.flashing.verify;
+    let flashed = config.flashing.enabled && !up_to_date;
 
+    // Start timer.
+    let instant = Instant::now();
+    // The time of flashing and verifying, without saving the caches and the layout afterwards.
+    let mut elapsed = Duration::default();
+
+    if flashed {
         if !opt.disable_progressbars {
             // Create progress bars.
             let multi_progress = MultiProgress::new();
@@ -342,12 +802,30 @@ fn main_try() -> Result<()> {
 
             // Create a new progress bar for the program progress.
             let program_progress = multi_progress.add(ProgressBar::new(0));
-            program_progress.set_style(style);
+            program_progress.set_style(style.clone());
             program_progress.set_message(" Programming pages  ");
 
-            let flash_layout_output_path = config.flashing.flash_layout_output_path.clone();
+            // Create a new progress bar for the verify progress if verifying is enabled.
+            let verify_progress = if verify {
+                let verify_progress = multi_progress.add(ProgressBar::new(
+                    images.iter().map(|image| image.size() as u64).sum(),
+                ));
+                verify_progress.set_style(style);
+                verify_progress.set_message("   Verifying flash  ");
+                Some(verify_progress)
+            } else {
+                None
+            };
+
+            // The other formats are written after flashing, when all flash regions are known.
+            let flash_layout_output_path = match config.flashing.flash_layout_output_format {
+                config::LayoutFormat::Svg => config.flashing.flash_layout_output_path.clone(),
+                _ => None,
+            };
+            let progress_recorder = recorder.clone();
             // Register callback to update the progress.
             let progress = FlashProgress::new(move |event| {
+                progress_recorder.record(&event);
                 use ProgressEvent::*;
                 match event {
                     Initialized { flash_layout } => {
@@ -427,43 +905,136 @@ fn main_try() -> Result<()> {
                 multi_progress.join().unwrap();
             });
 
-            download_file_with_options(
+            image::flash(
                 &mut session,
-                path.as_path(),
-                Format::Elf,
-                DownloadOptions {
-                    progress: Some(&progress),
-                    keep_unwritten_bytes: config.flashing.restore_unwritten_bytes,
-                },
+                &images,
+                config.flashing.restore_unwritten_bytes,
+                &progress,
             )
             .with_context(|| format!("failed to flash {}", path.display()))?;
 
+            let verification = match &verify_progress {
+                Some(verify_progress) => {
+                    verify_progress.enable_steady_tick(100);
+                    verify_progress.reset_elapsed();
+                    let verification =
+                        image::verify(&mut session, &images, |size| verify_progress.inc(size));
+                    if verification.is_ok() {
+                        verify_progress.finish();
+                    } else {
+                        verify_progress.abandon();
+                    }
+                    verification
+                }
+                None => Ok(()),
+            };
+
             // We don't care if we cannot join this thread.
             let _ = progress_thread_handle.join();
 
             // If we don't do this, the inactive progress bars will swallow log
             // messages, so they'll never be printed anywhere.
             logging::clear_progress_bar();
+
+            verification?;
         } else {
-            download_file_with_options(
+            image::flash(
                 &mut session,
-                path.as_path(),
-                Format::Elf,
-                DownloadOptions {
-                    progress: None,
-                    keep_unwritten_bytes: config.flashing.restore_unwritten_bytes,
-                },
+                &images,
+                config.flashing.restore_unwritten_bytes,
+                &FlashProgress::new({
+                    let recorder = recorder.clone();
+                    move |event| recorder.record(&event)
+                }),
             )
             .with_context(|| format!("failed to flash {}", path.display()))?;
+
+            if verify {
+                image::verify(&mut session, &images, |_| {})?;
+            }
         }
 
         // Stop timer.
-        let elapsed = instant.elapsed();
+        elapsed = instant.elapsed();
+
+        // The unit holds the patched values now, so the counters are advanced before anything
+        // else can fail and give the next unit the same values.
+        patch::commit(
+            &config.flashing.patch,
+            &patches,
+            config.flashing.patch_log.as_deref().map(Path::new),
+            &selection.to_string(),
+        )?;
+
+        if let (Some(mut rates), Some(chip_name)) = (plan::RateCache::load(&project), &chip_name) {
+            rates.update(chip_name, recorder.rates());
+            if let Err(err) = rates.save() {
+                log::warn!("Failed to save the flash rates: {:#}", err);
+            }
+        }
+        if let (Some(mut layouts), Some(chip_name)) =
+            (plan::LayoutCache::load(&project), &chip_name)
+        {
+            let layout = plan::FlashedLayout::new(
+                &images,
+                config.flashing.restore_unwritten_bytes,
+                recorder.layout(),
+            );
+            layouts.insert(chip_name, layout);
+            if let Err(err) = layouts.save() {
+                log::warn!("Failed to save the flash layout: {:#}", err);
+            }
+        }
         logging::println(format!(
-            "    {} flashing in {}s",
+            "    {} {} in {}s",
             "Finished".green().bold(),
+            if verify {
+                "flashing and verifying"
+            } else {
+                "flashing"
+            },
             elapsed.as_millis() as f32 / 1000.0,
         ));
+
+        let layout = recorder.layout();
+        let restore_unwritten_bytes = config.flashing.restore_unwritten_bytes;
+        if !layout.is_empty() {
+            for (i, line) in layout.map(restore_unwritten_bytes).iter().enumerate() {
+                let label = if i == 0 { "Layout" } else { "      " };
+                logging::println(format!("      {} {}", label.green().bold(), line));
+            }
+        }
+        if let Some(path) = &config.flashing.flash_layout_output_path {
+            let format = config.flashing.flash_layout_output_format;
+            if format != config::LayoutFormat::Svg {
+                layout.write(Path::new(path), format, restore_unwritten_bytes)?;
+            }
+        }
+    }
+
+    if config.flashing.enabled {
+        hooks.run(hooks::Hook::PostFlash)?;
+    }
+
+    if let Some(report_path) = &opt.report {
+        let report = report::Report {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            started: report_started.to_rfc3339(),
+            chip: chip_name.clone().or_else(|| probes::chip_name(&session)),
+            probe: report::ProbeReport {
+                name: probe_name,
+                selection: selection.to_string(),
+            },
+            speed_khz: protocol_speed,
+            elf_hash: elf.as_deref().map(report::hash_file).transpose()?,
+            elf: elf.clone(),
+            flashed,
+            verified: flashed && verify,
+            seconds: elapsed.as_secs_f64(),
+            phases: recorder.phases(),
+            timeline: recorder.timeline(),
+        };
+        report.write(report_path)?;
     }
 
     if config.reset.enabled {
@@ -472,7 +1043,7 @@ fn main_try() -> Result<()> {
         #[allow(deprecated)] // Remove in 0.10
         if config.flashing.halt_afterwards {
             logging::eprintln(format!(
-                "     {} The 'flashing.halt_afterwards' option in the config has moved to the 'reset' section",
+                "     {} The 'flashing.halt_afterwards' option in the config has moved to the 'reset' section. Run 'cargo embed config migrate' to update your config files.",
                 "Warning".yellow().bold()
             ));
             core.reset_and_halt(halt_timeout)?;
@@ -481,6 +1052,7 @@ fn main_try() -> Result<()> {
         } else {
             core.reset()?;
         }
+        hooks.run(hooks::Hook::PostReset)?;
     }
 
     let session = Arc::new(Mutex::new(session));
@@ -509,7 +1081,10 @@ fn main_try() -> Result<()> {
             .iter()
             .any(|elem| elem.format == DataFormat::Defmt);
         let defmt_state = if defmt_enable {
-            let elf = fs::read(path.clone()).unwrap();
+            let elf = elf.as_ref().ok_or_else(|| {
+                anyhow!("defmt channels need the ELF file of the firmware, use '--elf' to give it.")
+            })?;
+            let elf = fs::read(elf).with_context(|| format!("Failed to read {}", elf.display()))?;
             let table = defmt_elf2table::parse(&elf)?;
 
             let locs = {
@@ -540,7 +1115,7 @@ fn main_try() -> Result<()> {
             log::info!("Initializing RTT (attempt {})...", i);
             i += 1;
 
-            let rtt_header_address = if let Ok(mut file) = File::open(path.as_path()) {
+            let rtt_header_address = if let Some(Ok(mut file)) = elf.as_ref().map(File::open) {
                 if let Some(address) = rttui::app::App::get_rtt_symbol(&mut file) {
                     ScanRegion::Exact(address as u32)
                 } else {
@@ -608,6 +1183,271 @@ fn main_try() -> Result<()> {
     Ok(())
 }
 
+fn run_command(
+    opt: &Opt,
+    command: &Command,
+    work_dir: &Path,
+    project: &config::Project,
+    overrides: &[config::Override],
+) -> Result<()> {
+    let config_name = opt.config.as_deref().unwrap_or("default");
+
+    match command {
+        Command::Config(ConfigCommand::Show {
+            config,
+            format,
+            annotate,
+        }) => {
+            let config_name = config.as_deref().unwrap_or(config_name);
+            let mut resolved = config::Configs::try_new(config_name, project, overrides)
+                .with_context(|| format!("The config '{}' could not be loaded.", config_name))?;
+            resolved.interpolate(&opt.variables(config_name, project))?;
+            print_config_warnings(&resolved.warnings);
+
+            let output = match (format.as_str(), annotate) {
+                ("json", false) => resolved.to_json()?,
+                ("json", true) => resolved.to_annotated_json()?,
+                (_, false) => resolved.to_toml()?,
+                (_, true) => resolved.to_annotated_toml()?,
+            };
+            println!("{}", output);
+        }
+        Command::Config(ConfigCommand::Schema) => {
+            println!("{}", serde_json::to_string_pretty(&config::schema()?)?);
+        }
+        Command::Config(ConfigCommand::Migrate { yes }) => {
+            let migrations = config::migrations(project)?;
+            if migrations.is_empty() {
+                println!("No deprecated config keys were found.");
+                return Ok(());
+            }
+
+            for migration in &migrations {
+                for line in migration.diff().lines() {
+                    if line.starts_with("+++") || line.starts_with("---") {
+                        println!("{}", line.bold());
+                    } else if line.starts_with('+') {
+                        println!("{}", line.green());
+                    } else if line.starts_with('-') {
+                        println!("{}", line.red());
+                    } else if line.starts_with("@@") {
+                        println!("{}", line.cyan());
+                    } else {
+                        println!("{}", line);
+                    }
+                }
+                for change in &migration.changes {
+                    println!("  {}", change);
+                }
+                println!();
+            }
+
+            if !yes {
+                if !atty::is(atty::Stream::Stdin) {
+                    bail!("Nothing was written. Use '--yes' to write the changes without confirmation.");
+                }
+
+                print!("Write the changes? [y/N] ");
+                std::io::stdout().flush()?;
+                let mut answer = String::new();
+                std::io::stdin().read_line(&mut answer)?;
+                if !matches!(answer.trim(), "y" | "Y" | "yes") {
+                    println!("Nothing was written.");
+                    return Ok(());
+                }
+            }
+
+            for migration in &migrations {
+                migration.write()?;
+                logging::println(format!(
+                    "    {} {}",
+                    "Migrated".green().bold(),
+                    migration.path.display()
+                ));
+            }
+        }
+        Command::Probes => {
+            let aliases = config::Configs::load(project)
+                .map(|configs| configs.probes)
+                .unwrap_or_default();
+            let list = Probe::list_all();
+            if list.is_empty() {
+                println!("No supported probe was found.");
+            }
+            for (num, info) in list.iter().enumerate() {
+                match probes::alias_of(info, &aliases) {
+                    Some(alias) => println!("[{}] {} alias {}", num, probes::describe(info), alias),
+                    None => println!("[{}] {}", num, probes::describe(info)),
+                }
+            }
+            if !list.is_empty() {
+                println!("The firmware versions are not listed, probe-rs does not report them.");
+            }
+        }
+        Command::Doctor => {
+            let mut resolved = config::Configs::try_new(config_name, project, overrides)
+                .with_context(|| format!("The config '{}' could not be loaded.", config_name))?;
+            resolved.interpolate(&opt.variables(config_name, project))?;
+            let config = resolved.config;
+
+            logging::init(Some(config.general.log_level));
+            print_config_warnings(&resolved.warnings);
+
+            let (_, chip) = chip(opt, &config)?;
+            let selection = selection(opt, &config, &resolved.probes)?;
+
+            doctor::run(
+                &config,
+                selection.as_ref(),
+                chip,
+                work_dir,
+                &opt.cargo_build_args(),
+            )?;
+        }
+        Command::ProbeSpeed => {
+            let mut resolved = config::Configs::try_new(config_name, project, overrides)
+                .with_context(|| format!("The config '{}' could not be loaded.", config_name))?;
+            resolved.interpolate(&opt.variables(config_name, project))?;
+            let config = resolved.config;
+
+            logging::init(Some(config.general.log_level));
+            print_config_warnings(&resolved.warnings);
+
+            let (chip_name, chip) = chip(opt, &config)?;
+            let selection = match selection(opt, &config, &resolved.probes)? {
+                Some(selection) => selection,
+                None => probes::Selection::from(select_connected(
+                    &Probe::list_all(),
+                    &resolved.probes,
+                    project,
+                )?),
+            };
+
+            let speed = speed::measure(
+                || speed::open(&selection, config.probe.protocol),
+                &chip,
+                config.general.connect_under_reset,
+                speed::print_measurement,
+            )?;
+            logging::println(format!(
+                "     {} the fastest reliable speed is {} kHz",
+                "Finished".green().bold(),
+                speed
+            ));
+
+            let serial = Probe::list_all()
+                .into_iter()
+                .find(|info| selection.matches(info))
+                .and_then(|info| info.serial_number);
+            match (
+                config.probe.cache_speed,
+                speed::Cache::load(project),
+                serial,
+            ) {
+                (true, Some(mut cache), Some(serial)) => {
+                    cache.insert_speed(&serial, chip_name.as_deref().unwrap_or("auto"), speed);
+                    cache.save()?;
+                    logging::println(format!(
+                        "      {} for the probe {} in {}, used by 'speed = \"auto\"'",
+                        "Cached".green().bold(),
+                        serial,
+                        cache.path().display()
+                    ));
+                }
+                _ => logging::println(format!(
+                    "        {} set 'probe.speed = {}' or 'probe.speed = \"auto\"' to use it",
+                    "Hint".cyan().bold(),
+                    speed
+                )),
+            }
+        }
+        Command::Init { force } => {
+            logging::init(None);
+
+            // Aliases are only needed to resolve '--probe', so a config which cannot be
+            // loaded yet does not prevent generating a new one.
+            let aliases = config::Configs::load(project)
+                .map(|configs| configs.probes)
+                .unwrap_or_default();
+            let selection = opt
+                .probe_selector
+                .as_deref()
+                .map(|argument| probes::Selection::parse(argument, &aliases))
+                .transpose()?;
+
+            init::run(
+                project,
+                work_dir,
+                &opt.cargo_build_args(),
+                selection.as_ref(),
+                opt.chip.as_deref(),
+                *force,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the chip given with '--chip' or in the config, and the target selector for it.
+///
+/// Chip descriptions from 'general.chip_descriptions' are loaded first.
+fn chip(opt: &Opt, config: &config::Config) -> Result<(Option<String>, TargetSelector)> {
+    for cdp in &config.general.chip_descriptions {
+        probe_rs::config::add_target_from_yaml(Path::new(cdp))
+            .with_context(|| format!("failed to load the chip description from {}", cdp))?;
+    }
+
+    let chip_name = opt.chip.clone().or_else(|| config.general.chip.clone());
+    let chip = chip_name
+        .clone()
+        .map(|chip| chip.into())
+        .unwrap_or(TargetSelector::Auto);
+    Ok((chip_name, chip))
+}
+
+/// Returns the probe selected with '--probe' or in the config.
+fn selection(
+    opt: &Opt,
+    config: &config::Config,
+    aliases: &BTreeMap<String, config::ProbeAlias>,
+) -> Result<Option<probes::Selection>> {
+    match &opt.probe_selector {
+        Some(argument) => probes::Selection::parse(argument, aliases).map(Some),
+        None => probes::Selection::from_config(&config.probe, aliases),
+    }
+}
+
+fn print_config_warnings(warnings: &[String]) {
+    for warning in warnings {
+        logging::eprintln(format!("     {} {}", "Warning".yellow().bold(), warning));
+    }
+}
+
+/// Returns the only connected probe, or lets the user pick one if several are connected.
+fn select_connected<'a>(
+    list: &'a [DebugProbeInfo],
+    aliases: &BTreeMap<String, config::ProbeAlias>,
+    project: &config::Project,
+) -> Result<&'a DebugProbeInfo> {
+    // Only automatically select a probe if there is only
+    // a single probe detected, unless the user can pick one.
+    match list.len() {
+        0 => Err(anyhow!("No supported probe was found")),
+        1 => Ok(&list[0]),
+        _ if !atty::is(atty::Stream::Stdin) => Err(anyhow!("The following devices were found:\n \
+                            {} \
+                                \
+                            Use '--probe VID:PID'\n \
+                                                    \
+                            You can also set the [default.probe] config attribute \
+                            (in your Embed.toml) to select which probe to use. \
+                            For usage examples see https://github.com/probe-rs/cargo-embed/blob/master/src/config/default.toml .",
+                            list.iter().enumerate().map(|(num, link)| format!("[{}]: {:?}\n", num, link)).collect::<String>())),
+        _ => probes::pick(list, aliases, project),
+    }
+}
+
 fn print_families() -> Result<()> {
     logging::println("Available chips:");
     for family in
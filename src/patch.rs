@@ -0,0 +1,443 @@
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Local;
+use colored::*;
+use goblin::elf::{program_header::PT_LOAD, Elf};
+use probe_rs_cli_util::logging;
+use std::{
+    collections::BTreeSet,
+    convert::TryFrom,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use crate::{
+    config::{PatchConfig, PatchEncoding},
+    hooks,
+    image::Image,
+};
+
+/// A value which overwrites the bytes of a symbol in the images.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Patch {
+    pub symbol: String,
+    /// The address the symbol is flashed to.
+    pub address: u32,
+    /// The value as it was given.
+    pub value: String,
+    pub bytes: Vec<u8>,
+}
+
+/// Looks up the symbols of `flashing.patch` in the ELF file and gets their values.
+///
+/// Commands which give a value are run with the environment variables of the `hooks`.
+pub fn prepare(
+    configs: &[PatchConfig],
+    elf: Option<&Path>,
+    hooks: &hooks::Runner,
+) -> Result<Vec<Patch>> {
+    if configs.is_empty() {
+        return Ok(Vec::new());
+    }
+    let elf = elf.ok_or_else(|| {
+        anyhow!("Patching symbols needs the ELF file of the firmware, use '--elf' to give it.")
+    })?;
+    let bytes = fs::read(elf).with_context(|| format!("Failed to read {}", elf.display()))?;
+    let elf = Elf::parse(&bytes)?;
+
+    configs
+        .iter()
+        .map(|config| {
+            let (address, size) = find_symbol(&elf, &config.symbol)?;
+            let value = value(config, hooks)
+                .with_context(|| format!("Failed to get the value of {}", config.symbol))?;
+            let bytes = encode(&value, config.encoding.unwrap_or(PatchEncoding::Le), size)
+                .with_context(|| {
+                    format!(
+                        "The value '{}' does not fit {}, which has {} bytes",
+                        value, config.symbol, size
+                    )
+                })?;
+            Ok(Patch {
+                symbol: config.symbol.clone(),
+                address,
+                value,
+                bytes,
+            })
+        })
+        .collect()
+}
+
+/// Returns the address the symbol is flashed to and its size.
+///
+/// Symbols of initialized statics live in RAM, but their initial value is flashed
+/// at the load address of their segment.
+fn find_symbol(elf: &Elf, name: &str) -> Result<(u32, usize)> {
+    let symbol = elf
+        .syms
+        .iter()
+        .find(|symbol| matches!(elf.strtab.get(symbol.st_name), Some(Ok(symbol)) if symbol == name))
+        .ok_or_else(|| {
+            anyhow!(
+                "The symbol {} is not in the ELF file. Keep it with #[no_mangle] and #[used].",
+                name
+            )
+        })?;
+    if symbol.st_size == 0 {
+        bail!("The symbol {} has no size", name);
+    }
+
+    let address = symbol.st_value;
+    let header = elf
+        .program_headers
+        .iter()
+        .find(|header| {
+            header.p_type == PT_LOAD
+                && header.p_vaddr <= address
+                && address + symbol.st_size <= header.p_vaddr + header.p_filesz
+        })
+        .ok_or_else(|| {
+            anyhow!(
+                "The symbol {} is not flashed, it has to be a constant or an initialized static",
+                name
+            )
+        })?;
+    Ok((
+        u32::try_from(header.p_paddr + (address - header.p_vaddr))?,
+        symbol.st_size as usize,
+    ))
+}
+
+/// Returns the value of the patch from its source.
+fn value(config: &PatchConfig, hooks: &hooks::Runner) -> Result<String> {
+    match config {
+        PatchConfig {
+            value: Some(value),
+            counter: None,
+            csv: None,
+            command: None,
+            ..
+        } => Ok(value.clone()),
+        PatchConfig {
+            value: None,
+            counter: Some(counter),
+            csv: None,
+            command: None,
+            ..
+        } => Ok(read_counter(Path::new(counter))?.to_string()),
+        PatchConfig {
+            value: None,
+            counter: Some(counter),
+            csv: Some(csv),
+            column: Some(column),
+            command: None,
+            ..
+        } => {
+            let text = fs::read_to_string(csv).with_context(|| format!("Failed to read {}", csv))?;
+            csv_value(&text, column, read_counter(Path::new(counter))?)
+                .with_context(|| format!("Failed to get the value from {}", csv))
+        }
+        PatchConfig {
+            value: None,
+            counter: None,
+            csv: None,
+            command: Some(command),
+            ..
+        } => {
+            let output = hooks
+                .shell(command)
+                .env("EMBED_SYMBOL", &config.symbol)
+                .output()
+                .with_context(|| format!("Failed to run `{}`", command))?;
+            if !output.status.success() {
+                bail!(
+                    "`{}` failed with {}: {}",
+                    command,
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        }
+        _ => bail!(
+            "Give either a 'value', a 'counter', a 'csv' with a 'column' and a 'counter' for the row, or a 'command'."
+        ),
+    }
+}
+
+/// Reads the number in a counter file.
+fn read_counter(path: &Path) -> Result<u64> {
+    let text = fs::read_to_string(path).with_context(|| {
+        format!(
+            "Failed to read the counter {}, create it with the first number",
+            path.display()
+        )
+    })?;
+    text.trim()
+        .parse()
+        .with_context(|| format!("The counter {} does not contain a number", path.display()))
+}
+
+/// Returns the field in `column` of the data row with index `row` of a CSV file with a header.
+///
+/// Fields may be quoted, but must not contain commas.
+fn csv_value(text: &str, column: &str, row: u64) -> Result<String> {
+    let mut lines = text.lines().filter(|line| !line.trim().is_empty());
+    let fields = |line: &str| -> Vec<String> {
+        line.split(',')
+            .map(|field| field.trim().trim_matches('"').to_string())
+            .collect()
+    };
+
+    let header = fields(lines.next().ok_or_else(|| anyhow!("The file is empty"))?);
+    let index = header
+        .iter()
+        .position(|name| name == column)
+        .ok_or_else(|| {
+            anyhow!(
+                "There is no column '{}', the columns are {}",
+                column,
+                header.join(", ")
+            )
+        })?;
+    let line = lines
+        .nth(row as usize)
+        .ok_or_else(|| anyhow!("There is no row {}, all rows are used", row))?;
+    fields(line)
+        .get(index)
+        .cloned()
+        .ok_or_else(|| anyhow!("Row {} has no value in column '{}'", row, column))
+}
+
+/// Turns the value into exactly `size` bytes.
+fn encode(value: &str, encoding: PatchEncoding, size: usize) -> Result<Vec<u8>> {
+    let bytes = match encoding {
+        PatchEncoding::Le | PatchEncoding::Be => {
+            let number = match value
+                .strip_prefix("0x")
+                .or_else(|| value.strip_prefix("0X"))
+            {
+                Some(hex) => u128::from_str_radix(&hex.replace('_', ""), 16),
+                None => value.replace('_', "").parse(),
+            }
+            .with_context(|| format!("'{}' is not an unsigned integer", value))?;
+            if size < 16 && number >> (8 * size) != 0 {
+                bail!("{} needs more than {} bytes", number, size);
+            }
+            let mut bytes = number.to_le_bytes().to_vec();
+            bytes.resize(size, 0);
+            if encoding == PatchEncoding::Be {
+                bytes.reverse();
+            }
+            bytes
+        }
+        PatchEncoding::Hex => {
+            let digits: String = value
+                .chars()
+                .filter(|c| !matches!(c, ':' | '-' | ' '))
+                .collect();
+            let bytes = (0..digits.len())
+                .step_by(2)
+                .map(|i| {
+                    digits
+                        .get(i..i + 2)
+                        .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                })
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(|| anyhow!("'{}' does not consist of hex bytes", value))?;
+            if bytes.len() != size {
+                bail!("{} bytes are given", bytes.len());
+            }
+            bytes
+        }
+        PatchEncoding::String => {
+            let mut bytes = value.as_bytes().to_vec();
+            if bytes.len() > size {
+                bail!("The text has {} bytes", bytes.len());
+            }
+            bytes.resize(size, 0);
+            bytes
+        }
+    };
+    Ok(bytes)
+}
+
+/// Overwrites the bytes of the symbols in the images.
+pub fn apply(images: &mut [Image], patches: &[Patch]) -> Result<()> {
+    for patch in patches {
+        let start = patch.address as u64;
+        let end = start + patch.bytes.len() as u64;
+        let segment = images
+            .iter_mut()
+            .flat_map(|image| image.segments.iter_mut())
+            .find(|segment| segment.range().start <= start && end <= segment.range().end)
+            .ok_or_else(|| {
+                anyhow!(
+                    "No image contains {} at {:#010x}",
+                    patch.symbol,
+                    patch.address
+                )
+            })?;
+        let offset = (start - segment.range().start) as usize;
+        segment.data[offset..offset + patch.bytes.len()].copy_from_slice(&patch.bytes);
+    }
+    Ok(())
+}
+
+/// Prints the values written to the unit, increments the counters used by the patches
+/// and logs the values.
+///
+/// This is done after flashing succeeded, so a unit which failed or was skipped because
+/// it is up to date does not use up a value.
+pub fn commit(
+    configs: &[PatchConfig],
+    patches: &[Patch],
+    log: Option<&Path>,
+    unit: &str,
+) -> Result<()> {
+    if patches.is_empty() {
+        return Ok(());
+    }
+
+    for patch in patches {
+        logging::println(format!(
+            "     {} {} = {} at {:#010x}",
+            "Patched".green().bold(),
+            patch.symbol,
+            patch.value,
+            patch.address
+        ));
+    }
+
+    // A counter which selects a CSV row and is a value as well is only incremented once.
+    let counters: BTreeSet<_> = configs
+        .iter()
+        .filter_map(|config| config.counter.as_deref())
+        .collect();
+    for counter in counters {
+        let path = Path::new(counter);
+        let next = read_counter(path)? + 1;
+        fs::write(path, format!("{}\n", next))
+            .with_context(|| format!("Failed to write the counter {}", path.display()))?;
+    }
+
+    let values: Vec<_> = patches
+        .iter()
+        .map(|patch| format!("{}={}", patch.symbol, patch.value))
+        .collect();
+    log::info!("Patched {} with {}", unit, values.join(", "));
+    if let Some(log) = log {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log)
+            .with_context(|| format!("Failed to open {}", log.display()))?;
+        writeln!(
+            file,
+            "{},{},{}",
+            Local::now().to_rfc3339(),
+            unit,
+            values.join(",")
+        )
+        .with_context(|| format!("Failed to write to {}", log.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply, commit, csv_value, encode, Patch};
+    use crate::{
+        config::{PatchConfig, PatchEncoding},
+        image::{Image, ImageFormat, Segment},
+        test_util::TempDir,
+    };
+
+    #[test]
+    fn encodes_values() {
+        assert_eq!(
+            encode("0x1234", PatchEncoding::Le, 4).unwrap(),
+            [0x34, 0x12, 0, 0]
+        );
+        assert_eq!(encode("258", PatchEncoding::Be, 2).unwrap(), [0x01, 0x02]);
+        assert!(encode("65536", PatchEncoding::Le, 2).is_err());
+        assert_eq!(
+            encode("02:00:5e:10:00:01", PatchEncoding::Hex, 6).unwrap(),
+            [0x02, 0x00, 0x5e, 0x10, 0x00, 0x01]
+        );
+        assert!(encode("02:00:5e", PatchEncoding::Hex, 6).is_err());
+        assert_eq!(
+            encode("SN1", PatchEncoding::String, 5).unwrap(),
+            [b'S', b'N', b'1', 0, 0]
+        );
+        assert!(encode("SN123456", PatchEncoding::String, 5).is_err());
+    }
+
+    #[test]
+    fn reads_csv_rows_and_patches_images() {
+        let csv = "serial, mac\n\
+                   A-1, \"02:00:5e:10:00:01\"\n\
+                   A-2, 02:00:5e:10:00:02\n";
+        assert_eq!(csv_value(csv, "mac", 0).unwrap(), "02:00:5e:10:00:01");
+        assert_eq!(csv_value(csv, "serial", 1).unwrap(), "A-2");
+        assert!(csv_value(csv, "mac", 2).is_err());
+        assert!(csv_value(csv, "key", 0).is_err());
+
+        let mut images = [Image {
+            path: "app".into(),
+            format: ImageFormat::Elf,
+            segments: vec![Segment {
+                address: 0x0800_0000,
+                data: vec![0; 16],
+            }],
+        }];
+        let patch = |address, bytes: &[u8]| Patch {
+            symbol: "SERIAL_NUMBER".to_string(),
+            address,
+            value: "1".to_string(),
+            bytes: bytes.to_vec(),
+        };
+        apply(&mut images, &[patch(0x0800_0004, &[1, 2])]).unwrap();
+        assert_eq!(images[0].segments[0].data[3..7], [0, 1, 2, 0]);
+        assert!(apply(&mut images, &[patch(0x0800_000f, &[1, 2])]).is_err());
+    }
+
+    #[test]
+    fn commits_counters_once_and_logs_the_unit() {
+        let dir = TempDir::new("commits-counters");
+        let counter = dir.write("row.txt", "41\n");
+        let log = dir.path().join("units.csv");
+        let config = |symbol: &str| PatchConfig {
+            symbol: symbol.to_string(),
+            encoding: None,
+            value: None,
+            counter: Some(counter.display().to_string()),
+            csv: None,
+            column: None,
+            command: None,
+        };
+        let patch = |symbol: &str, value: &str| Patch {
+            symbol: symbol.to_string(),
+            address: 0x0800_0000,
+            value: value.to_string(),
+            bytes: vec![],
+        };
+
+        commit(
+            &[config("SERIAL_NUMBER"), config("MAC_ADDRESS")],
+            &[
+                patch("SERIAL_NUMBER", "41"),
+                patch("MAC_ADDRESS", "02:00:5e:10:00:01"),
+            ],
+            Some(&log),
+            "J-Link",
+        )
+        .unwrap();
+
+        // Both symbols share the counter, which is only advanced once per unit.
+        assert_eq!(std::fs::read_to_string(&counter).unwrap(), "42\n");
+        assert!(std::fs::read_to_string(&log)
+            .unwrap()
+            .ends_with(",J-Link,SERIAL_NUMBER=41,MAC_ADDRESS=02:00:5e:10:00:01\n"));
+    }
+}
@@ -0,0 +1,677 @@
+use anyhow::{anyhow, bail, Context, Result};
+use colored::*;
+use probe_rs::{
+    config::{MemoryRegion, TargetSelector},
+    Target,
+};
+use probe_rs_cli_util::logging;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeSet, ops::Range, path::Path, str::FromStr};
+
+use crate::{cache, config::LayoutFormat, image::Image};
+
+/// How a flash plan is printed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "'{}' is not an output format, use 'text' or 'json'",
+                s
+            )),
+        }
+    }
+}
+
+/// A block of flash memory which is erased, programmed or filled.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct Block {
+    pub address: u32,
+    pub size: u32,
+}
+
+impl Block {
+    fn range(&self) -> Range<u64> {
+        self.address as u64..self.address as u64 + self.size as u64
+    }
+}
+
+/// The sectors, pages and fills of flashing.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Layout {
+    /// The sectors which are erased.
+    pub sectors: Vec<Block>,
+    /// The pages which are programmed.
+    pub pages: Vec<Block>,
+    /// The parts of the programmed pages which contain no data from the images.
+    pub fills: Vec<Block>,
+}
+
+/// The width of the bar map of the flash layout in characters.
+const MAP_WIDTH: u64 = 64;
+
+impl Layout {
+    /// Adds the blocks of the layout of another flash region.
+    pub fn extend(&mut self, other: Layout) {
+        self.sectors.extend(other.sectors);
+        self.pages.extend(other.pages);
+        self.fills.extend(other.fills);
+        for blocks in &mut [&mut self.sectors, &mut self.pages, &mut self.fills] {
+            blocks.sort_by_key(|block| block.address);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sectors.is_empty()
+    }
+
+    /// Returns a table of all blocks with the totals.
+    pub fn table(&self, restore_unwritten_bytes: bool) -> String {
+        let mut table = format!("{:<8} {:<12} {:>10}\n", "Action", "Address", "Size");
+        for (action, blocks) in &[
+            ("erase", &self.sectors),
+            ("program", &self.pages),
+            ("fill", &self.fills),
+        ] {
+            for block in blocks.iter() {
+                table += &format!(
+                    "{:<8} {:#010x}   {:>10}\n",
+                    action, block.address, block.size
+                );
+            }
+        }
+
+        let total = |blocks: &[Block]| blocks.iter().map(|block| block.size as u64).sum::<u64>();
+        table += &format!(
+            "\nSectors to erase:  {} ({} bytes)\n",
+            self.sectors.len(),
+            total(&self.sectors)
+        );
+        table += &format!(
+            "Pages to program:  {} ({} bytes)\n",
+            self.pages.len(),
+            total(&self.pages)
+        );
+        table += &format!(
+            "Fill regions:      {} ({} bytes, {})\n",
+            self.fills.len(),
+            total(&self.fills),
+            if restore_unwritten_bytes {
+                "restored from the flash"
+            } else {
+                "padded with the erased value"
+            }
+        );
+        table
+    }
+
+    /// Returns a bar map for every run of adjacent erased sectors, followed by a legend.
+    ///
+    /// Every character covers an equal part of the sectors and shows the most important
+    /// thing which happened to it: data was written, bytes were restored or it was only erased.
+    pub fn map(&self, restore_unwritten_bytes: bool) -> Vec<String> {
+        let mut runs: Vec<Range<u64>> = Vec::new();
+        for sector in &self.sectors {
+            let range = sector.range();
+            match runs.last_mut() {
+                Some(run) if run.end == range.start => run.end = range.end,
+                _ => runs.push(range),
+            }
+        }
+
+        let overlap = |blocks: &[Block], range: &Range<u64>| {
+            blocks
+                .iter()
+                .map(|block| {
+                    let block = block.range();
+                    block
+                        .end
+                        .min(range.end)
+                        .saturating_sub(block.start.max(range.start))
+                })
+                .sum::<u64>()
+        };
+        let mut lines: Vec<String> = runs
+            .iter()
+            .map(|run| {
+                let cell = (run.end - run.start - 1) / MAP_WIDTH + 1;
+                let bar: String = (0..MAP_WIDTH)
+                    .map(|i| run.start + i * cell..(run.start + (i + 1) * cell).min(run.end))
+                    .filter(|cell| cell.start < cell.end)
+                    .map(|cell| {
+                        let filled = overlap(&self.fills, &cell);
+                        if overlap(&self.pages, &cell) > filled {
+                            '█'
+                        } else if restore_unwritten_bytes && filled > 0 {
+                            '▒'
+                        } else {
+                            '░'
+                        }
+                    })
+                    .collect();
+                format!("{:#010x} {} {:#010x}", run.start, bar, run.end)
+            })
+            .collect();
+        lines.push(if restore_unwritten_bytes {
+            "█ written  ▒ restored  ░ erased".to_string()
+        } else {
+            "█ written  ░ erased".to_string()
+        });
+        lines
+    }
+
+    /// Writes the layout to `path` as JSON or as a table with the bar map.
+    ///
+    /// SVGs are written by the visualizer of probe-rs instead.
+    pub fn write(
+        &self,
+        path: &Path,
+        format: LayoutFormat,
+        restore_unwritten_bytes: bool,
+    ) -> Result<()> {
+        let contents = match format {
+            LayoutFormat::Json => serde_json::to_string_pretty(self)?,
+            LayoutFormat::Text => format!(
+                "{}\n{}\n",
+                self.table(restore_unwritten_bytes),
+                self.map(restore_unwritten_bytes).join("\n")
+            ),
+            LayoutFormat::Svg => bail!("SVGs are written by the flash layout visualizer"),
+        };
+        std::fs::write(path, contents)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// What flashing the images would do, without touching the target.
+#[derive(Debug, Serialize)]
+pub struct Plan {
+    pub chip: String,
+    #[serde(flatten)]
+    pub layout: Layout,
+    /// Whether the fills are read from the flash before erasing and restored,
+    /// otherwise they are padded with the erased value.
+    pub restore_unwritten_bytes: bool,
+    /// The estimated time in seconds, if the chip has been flashed before.
+    pub estimated_seconds: Option<f64>,
+    /// Whether the layout is the one the flash loader used the last time the same data
+    /// was flashed, if it was flashed before.
+    pub matches_last_flash: Option<bool>,
+}
+
+/// A flash region of the chip with the layout of its flash algorithm.
+#[derive(Debug)]
+struct FlashRegion {
+    range: Range<u64>,
+    page_size: u64,
+    /// The absolute start addresses from which on sectors have the given size, ordered by address.
+    sectors: Vec<(u64, u64)>,
+}
+
+impl FlashRegion {
+    /// Returns the sector containing `address`.
+    fn sector(&self, address: u64) -> Result<Range<u64>> {
+        let (start, size) = self
+            .sectors
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= address)
+            .ok_or_else(|| anyhow!("The flash algorithm has no sector at {:#010x}", address))?;
+        let sector = start + (address - start) / size * size;
+        Ok(sector..sector + size)
+    }
+
+    /// Returns the page containing `address`.
+    fn page(&self, address: u64) -> Range<u64> {
+        let page =
+            self.range.start + (address - self.range.start) / self.page_size * self.page_size;
+        page..page + self.page_size
+    }
+}
+
+impl Plan {
+    /// Plans flashing the images to the target the same way the flash loader does.
+    ///
+    /// The layout is compared with the one the flash loader used the last time,
+    /// if the same blocks of data were flashed with the same setting for unwritten bytes.
+    pub fn build(
+        target: &Target,
+        images: &[Image],
+        restore_unwritten_bytes: bool,
+        flashed: Option<&FlashedLayout>,
+        rates: Option<&Rates>,
+    ) -> Result<Self> {
+        let data: Vec<Range<u64>> = data(images).iter().map(Block::range).collect();
+        let layout = layout(&flash_regions(target), &data, restore_unwritten_bytes)?;
+        let matches_last_flash = flashed
+            .and_then(|flashed| flashed.layout_for(images, restore_unwritten_bytes))
+            .map(|flashed| *flashed == layout);
+        let estimated_seconds =
+            rates.and_then(|rates| rates.estimate(&layout, restore_unwritten_bytes));
+
+        Ok(Self {
+            chip: target.name.clone(),
+            layout,
+            restore_unwritten_bytes,
+            estimated_seconds,
+            matches_last_flash,
+        })
+    }
+
+    /// Prints the plan as a table with the totals.
+    pub fn print(&self, format: OutputFormat) -> Result<()> {
+        if format == OutputFormat::Json {
+            println!("{}", serde_json::to_string_pretty(self)?);
+            return Ok(());
+        }
+
+        logging::println(format!(
+            "     {} {}, nothing is erased or programmed",
+            "Dry run".green().bold(),
+            self.chip
+        ));
+        print!("{}", self.layout.table(self.restore_unwritten_bytes));
+        match self.estimated_seconds {
+            Some(seconds) => println!("Estimated time:    {:.1}s", seconds),
+            None => println!(
+                "Estimated time:    unknown, it is measured the next time {} is flashed",
+                self.chip
+            ),
+        }
+        if self.matches_last_flash == Some(false) {
+            log::warn!(
+                "The flash loader laid out the same data differently the last time {} was flashed.",
+                self.chip
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Returns the blocks of data in the images, ordered by address.
+fn data(images: &[Image]) -> Vec<Block> {
+    let mut data: Vec<Block> = images
+        .iter()
+        .flat_map(|image| &image.segments)
+        .map(|segment| Block {
+            address: segment.address,
+            size: segment.data.len() as u32,
+        })
+        .collect();
+    data.sort_by_key(|block| block.address);
+    data
+}
+
+/// Returns the description of the chip, which is needed to plan without attaching.
+pub fn target(chip: &TargetSelector) -> Result<Target> {
+    match chip {
+        TargetSelector::Unspecified(name) => Ok(probe_rs::config::get_target_by_name(name)?),
+        TargetSelector::Specified(target) => Ok(target.clone()),
+        TargetSelector::Auto => {
+            bail!("A dry run needs to know the chip, set it with '--chip' or 'general.chip'.")
+        }
+    }
+}
+
+/// Returns the flash regions of the target which have a flash algorithm.
+///
+/// Like the flash loader, the default algorithm is used if several cover a region.
+fn flash_regions(target: &Target) -> Vec<FlashRegion> {
+    let mut regions = Vec::new();
+    for region in &target.memory_map {
+        let region = match region {
+            MemoryRegion::Nvm(region) => region,
+            _ => continue,
+        };
+        let algorithms: Vec<_> = target
+            .flash_algorithms
+            .iter()
+            .filter(|algorithm| {
+                let range = &algorithm.flash_properties.address_range;
+                range.start <= region.range.start && region.range.end <= range.end
+            })
+            .collect();
+        let algorithm = match algorithms.as_slice() {
+            [algorithm] => algorithm,
+            _ => match algorithms.iter().find(|algorithm| algorithm.default) {
+                Some(algorithm) => algorithm,
+                None => {
+                    log::debug!(
+                        "No flash algorithm for the region at {:#010x}",
+                        region.range.start
+                    );
+                    continue;
+                }
+            },
+        };
+        let properties = &algorithm.flash_properties;
+        regions.push(FlashRegion {
+            range: region.range.start as u64..region.range.end as u64,
+            page_size: properties.page_size as u64,
+            sectors: properties
+                .sectors
+                .iter()
+                .map(|sector| {
+                    (
+                        properties.address_range.start as u64 + sector.address as u64,
+                        sector.size as u64,
+                    )
+                })
+                .collect(),
+        });
+    }
+    regions
+}
+
+/// Returns the sectors which are erased, the pages which are programmed and the fills
+/// for the `data` ranges, which have to be ordered by address.
+///
+/// If unwritten bytes are restored, every page of the erased sectors is programmed again.
+fn layout(
+    regions: &[FlashRegion],
+    data: &[Range<u64>],
+    restore_unwritten_bytes: bool,
+) -> Result<Layout> {
+    let mut sectors = BTreeSet::new();
+    let mut pages = BTreeSet::new();
+
+    for range in data {
+        let mut address = range.start;
+        while address < range.end {
+            let region = regions
+                .iter()
+                .find(|region| region.range.contains(&address))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "No flash region of the chip contains the data at {:#010x}",
+                        address
+                    )
+                })?;
+            let sector = region.sector(address)?;
+            let page = region.page(address);
+            if restore_unwritten_bytes && !sectors.contains(&(sector.start, sector.end)) {
+                let mut page = region.page(sector.start);
+                while page.start < sector.end {
+                    pages.insert((page.start, page.end));
+                    page = region.page(page.end);
+                }
+            }
+            sectors.insert((sector.start, sector.end));
+            pages.insert((page.start, page.end));
+            address = page.end;
+        }
+    }
+
+    let mut fills = Vec::new();
+    for &(start, end) in &pages {
+        let mut cursor = start;
+        for range in data
+            .iter()
+            .filter(|range| range.end > start && range.start < end)
+        {
+            if range.start > cursor {
+                fills.push(block(cursor..range.start));
+            }
+            cursor = cursor.max(range.end);
+        }
+        if cursor < end {
+            fills.push(block(cursor..end));
+        }
+    }
+
+    let blocks = |set: BTreeSet<(u64, u64)>| {
+        set.into_iter()
+            .map(|(start, end)| block(start..end))
+            .collect()
+    };
+    Ok(Layout {
+        sectors: blocks(sectors),
+        pages: blocks(pages),
+        fills,
+    })
+}
+
+fn block(range: Range<u64>) -> Block {
+    Block {
+        address: range.start as u32,
+        size: (range.end - range.start) as u32,
+    }
+}
+
+/// The layout the flash loader used the last time a chip was flashed.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FlashedLayout {
+    /// The blocks of data which were flashed.
+    pub data: Vec<Block>,
+    pub restore_unwritten_bytes: bool,
+    pub layout: Layout,
+}
+
+impl FlashedLayout {
+    pub fn new(images: &[Image], restore_unwritten_bytes: bool, layout: Layout) -> Self {
+        Self {
+            data: data(images),
+            restore_unwritten_bytes,
+            layout,
+        }
+    }
+
+    /// Returns the layout if the same blocks of data were flashed with the same setting
+    /// for unwritten bytes, as the layout depends on both.
+    pub fn layout_for(&self, images: &[Image], restore_unwritten_bytes: bool) -> Option<&Layout> {
+        if self.data == data(images) && self.restore_unwritten_bytes == restore_unwritten_bytes {
+            Some(&self.layout)
+        } else {
+            None
+        }
+    }
+}
+
+impl cache::Entry for FlashedLayout {
+    const FILE_NAME: &'static str = "flash-layouts.toml";
+}
+
+/// The layouts of the last time every chip was flashed, by chip.
+pub type LayoutCache = cache::Cache<FlashedLayout>;
+
+/// How fast the flash of a chip was erased, programmed and read, in bytes per second.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub struct Rates {
+    pub erase: Option<f64>,
+    pub program: Option<f64>,
+    pub fill: Option<f64>,
+}
+
+impl Rates {
+    /// Returns the time in seconds flashing the layout takes, if all the needed rates are known.
+    fn estimate(&self, layout: &Layout, restore_unwritten_bytes: bool) -> Option<f64> {
+        let seconds = |blocks: &[Block], rate: Option<f64>| {
+            let bytes = blocks.iter().map(|block| block.size as f64).sum::<f64>();
+            if bytes == 0.0 {
+                Some(0.0)
+            } else {
+                rate.map(|rate| bytes / rate)
+            }
+        };
+        let fills = if restore_unwritten_bytes {
+            seconds(&layout.fills, self.fill)?
+        } else {
+            0.0
+        };
+        Some(seconds(&layout.sectors, self.erase)? + seconds(&layout.pages, self.program)? + fills)
+    }
+
+    /// Takes the rates which were measured in `other`.
+    fn update(&mut self, other: Rates) {
+        self.erase = other.erase.or(self.erase);
+        self.program = other.program.or(self.program);
+        self.fill = other.fill.or(self.fill);
+    }
+}
+
+impl cache::Entry for Rates {
+    const FILE_NAME: &'static str = "flash-rates.toml";
+}
+
+/// The rates measured when flashing, by chip.
+pub type RateCache = cache::Cache<Rates>;
+
+impl RateCache {
+    /// Stores the rates measured for the chip, keeping the ones measured before which are missing.
+    pub fn update(&mut self, chip: &str, rates: Rates) {
+        let mut updated = self.get(chip).copied().unwrap_or_default();
+        updated.update(rates);
+        self.insert(chip, updated);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{layout, Block, FlashRegion, FlashedLayout, Layout, RateCache, Rates};
+    use crate::{
+        image::{Image, ImageFormat, Segment},
+        test_util::TempDir,
+    };
+
+    fn block(address: u32, size: u32) -> Block {
+        Block { address, size }
+    }
+
+    fn image(address: u32, size: usize) -> Image {
+        Image {
+            path: "app".into(),
+            format: ImageFormat::Elf,
+            segments: vec![Segment {
+                address,
+                data: vec![0; size],
+            }],
+        }
+    }
+
+    /// The layout the flash loader uses for 4 bytes at 0x1000 with 4 KiB sectors and 1 KiB pages.
+    fn restored_layout() -> Layout {
+        Layout {
+            sectors: vec![block(0x1000, 0x1000)],
+            pages: vec![
+                block(0x1000, 0x400),
+                block(0x1400, 0x400),
+                block(0x1800, 0x400),
+                block(0x1c00, 0x400),
+            ],
+            fills: vec![
+                block(0x1004, 0x3fc),
+                block(0x1400, 0x400),
+                block(0x1800, 0x400),
+                block(0x1c00, 0x400),
+            ],
+        }
+    }
+
+    #[test]
+    fn plans_sectors_pages_and_fills() {
+        // 4 KiB sectors with 1 KiB pages, then 16 KiB sectors from 0x8000 on.
+        let regions = [FlashRegion {
+            range: 0x0..0x1_0000,
+            page_size: 0x400,
+            sectors: vec![(0x0, 0x1000), (0x8000, 0x4000)],
+        }];
+
+        let Layout {
+            sectors,
+            pages,
+            fills,
+        } = layout(&regions, &[0x100..0x500, 0x8000..0x8010], false).unwrap();
+        assert_eq!(sectors, [block(0x0, 0x1000), block(0x8000, 0x4000)]);
+        assert_eq!(
+            pages,
+            [block(0x0, 0x400), block(0x400, 0x400), block(0x8000, 0x400)]
+        );
+        assert_eq!(
+            fills,
+            [block(0x0, 0x100), block(0x500, 0x300), block(0x8010, 0x3f0)]
+        );
+
+        assert_eq!(
+            layout(&regions, &[0x1000..0x1004], true).unwrap(),
+            restored_layout()
+        );
+
+        assert!(layout(&regions, &[0x8000..0x8004, 0x1_0000..0x1_0004], false).is_err());
+    }
+
+    #[test]
+    fn compares_with_the_flashed_layout_of_the_same_data() {
+        let images = [image(0x1000, 4)];
+        let flashed = FlashedLayout::new(&images, true, restored_layout());
+        assert_eq!(flashed.layout_for(&images, true), Some(&restored_layout()));
+
+        // The layout depends on the data blocks and on restoring unwritten bytes.
+        assert_eq!(flashed.layout_for(&[image(0x1000, 8)], true), None);
+        assert_eq!(flashed.layout_for(&images, false), None);
+    }
+
+    #[test]
+    fn maps_the_layout() {
+        // Every character covers 64 bytes of the sector.
+        let map = restored_layout().map(true);
+        assert_eq!(map[0], format!("0x00001000 █{} 0x00002000", "▒".repeat(63)));
+        assert_eq!(map[1], "█ written  ▒ restored  ░ erased");
+    }
+
+    #[test]
+    fn estimates_from_rates() {
+        let layout = Layout {
+            sectors: vec![block(0, 4096)],
+            pages: vec![block(0, 1024)],
+            fills: vec![block(1000, 24)],
+        };
+        let rates = Rates {
+            erase: Some(4096.0),
+            program: Some(1024.0),
+            fill: None,
+        };
+        assert_eq!(rates.estimate(&layout, false), Some(2.0));
+        // The fills only take time when they are restored.
+        assert_eq!(rates.estimate(&layout, true), None);
+    }
+
+    #[test]
+    fn keeps_cached_rates_which_were_not_measured() {
+        let dir = TempDir::new("keeps-cached-rates");
+        let mut cache = RateCache::load_from(dir.path().join("flash-rates.toml"));
+        assert_eq!(cache.get("nRF52840_xxAA"), None);
+        cache.update(
+            "nRF52840_xxAA",
+            Rates {
+                erase: Some(4096.0),
+                program: Some(1024.0),
+                fill: None,
+            },
+        );
+        cache.update(
+            "nRF52840_xxAA",
+            Rates {
+                erase: None,
+                program: Some(2048.0),
+                fill: Some(512.0),
+            },
+        );
+        assert_eq!(
+            cache.get("nRF52840_xxAA"),
+            Some(&Rates {
+                erase: Some(4096.0),
+                program: Some(2048.0),
+                fill: Some(512.0),
+            })
+        );
+    }
+}
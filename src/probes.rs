@@ -0,0 +1,533 @@
+use anyhow::{anyhow, bail, Context, Result};
+use colored::*;
+use indicatif::{ProgressBar, ProgressStyle};
+use probe_rs::{config::families, DebugProbeInfo, DebugProbeSelector, Probe, Session};
+use probe_rs_cli_util::logging;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use toml_edit::{table, value, Document, Item, Table};
+
+use crate::config::{self, ProbeAlias, Project};
+
+/// A probe selected with `--probe`, `probe.alias` or `probe.usb_vid` and `probe.usb_pid`.
+#[derive(Debug, Clone)]
+pub struct Selection {
+    /// The name of the alias the probe was selected with, if any.
+    pub alias: Option<String>,
+    pub selector: DebugProbeSelector,
+}
+
+impl Selection {
+    /// Parses a `--probe` argument, which is either the name of an alias or `VID:PID[:Serial]`.
+    pub fn parse(argument: &str, aliases: &BTreeMap<String, ProbeAlias>) -> Result<Self> {
+        if let Some(alias) = aliases.get(argument) {
+            return Self::from_alias(argument, alias);
+        }
+
+        let selector = argument.parse::<DebugProbeSelector>().map_err(|_| {
+            anyhow!(
+                "'{}' is neither a probe alias nor 'VID:PID[:Serial]' (known aliases: {})",
+                argument,
+                alias_names(aliases)
+            )
+        })?;
+        Ok(Self {
+            alias: None,
+            selector,
+        })
+    }
+
+    /// Returns the probe selected in the config, if any.
+    ///
+    /// `probe.alias` takes precedence over `probe.usb_vid` and `probe.usb_pid`.
+    pub fn from_config(
+        probe: &config::Probe,
+        aliases: &BTreeMap<String, ProbeAlias>,
+    ) -> Result<Option<Self>> {
+        if let Some(name) = &probe.alias {
+            let alias = aliases.get(name).ok_or_else(|| {
+                anyhow!(
+                    "The probe alias '{}' set in 'probe.alias' is not defined in the [probes] table (known aliases: {})",
+                    name,
+                    alias_names(aliases)
+                )
+            })?;
+            return Self::from_alias(name, alias).map(Some);
+        }
+
+        match (probe.usb_vid.as_ref(), probe.usb_pid.as_ref()) {
+            (Some(vid), Some(pid)) => Ok(Some(Self {
+                alias: None,
+                selector: DebugProbeSelector {
+                    vendor_id: u16::from_str_radix(vid, 16)?,
+                    product_id: u16::from_str_radix(pid, 16)?,
+                    serial_number: probe.serial.clone(),
+                },
+            })),
+            _ => {
+                if probe.usb_vid.is_some() {
+                    log::warn!("USB VID ignored, because PID is not specified.");
+                }
+                if probe.usb_pid.is_some() {
+                    log::warn!("USB PID ignored, because VID is not specified.");
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    fn from_alias(name: &str, alias: &ProbeAlias) -> Result<Self> {
+        let parse = |id: &str, key: &str| {
+            u16::from_str_radix(id, 16).with_context(|| {
+                format!(
+                    "The '{}' of the probe alias '{}' is not a hex number: {}",
+                    key, name, id
+                )
+            })
+        };
+
+        Ok(Self {
+            alias: Some(name.to_string()),
+            selector: DebugProbeSelector {
+                vendor_id: parse(&alias.usb_vid, "usb_vid")?,
+                product_id: parse(&alias.usb_pid, "usb_pid")?,
+                serial_number: alias.serial.clone(),
+            },
+        })
+    }
+
+    /// Returns whether the given probe is the selected one.
+    pub fn matches(&self, info: &DebugProbeInfo) -> bool {
+        info.vendor_id == self.selector.vendor_id
+            && info.product_id == self.selector.product_id
+            && (self.selector.serial_number.is_none()
+                || info.serial_number == self.selector.serial_number)
+    }
+
+    /// Opens the selected probe.
+    ///
+    /// If the probe was selected by its alias and it is not connected, the error names the alias.
+    pub fn open(&self) -> Result<Probe> {
+        if self.alias.is_some() && !Probe::list_all().iter().any(|info| self.matches(info)) {
+            bail!("The probe {} is not connected", self);
+        }
+
+        Probe::open(self.selector.clone())
+            .with_context(|| format!("Failed to open the probe {}", self))
+    }
+}
+
+impl From<&DebugProbeInfo> for Selection {
+    fn from(info: &DebugProbeInfo) -> Self {
+        Self {
+            alias: None,
+            selector: info.into(),
+        }
+    }
+}
+
+impl fmt::Display for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(alias) = &self.alias {
+            write!(f, "{} (", alias)?;
+        }
+        write!(
+            f,
+            "{:04x}:{:04x}",
+            self.selector.vendor_id, self.selector.product_id
+        )?;
+        if let Some(serial) = &self.selector.serial_number {
+            write!(f, ":{}", serial)?;
+        }
+        if self.alias.is_some() {
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+/// Calls `attempt` until it succeeds or `deadline` has passed, backing off between attempts.
+///
+/// While waiting, a spinner shows why the last attempt failed.
+/// If the deadline passes, the error of the last attempt is returned.
+pub fn wait_until<T>(
+    deadline: Instant,
+    show_progress: bool,
+    mut attempt: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let start = Instant::now();
+    let mut delay = Duration::from_millis(100);
+    let mut spinner: Option<ProgressBar> = None;
+
+    loop {
+        let error = match attempt() {
+            Ok(value) => {
+                if let Some(spinner) = spinner {
+                    spinner.finish_and_clear();
+                }
+                return Ok(value);
+            }
+            Err(error) => error,
+        };
+
+        let now = Instant::now();
+        if now >= deadline {
+            return match spinner {
+                Some(spinner) => {
+                    spinner.finish_and_clear();
+                    Err(error.context(format!(
+                        "Gave up waiting after {:.1}s",
+                        (now - start).as_secs_f32()
+                    )))
+                }
+                None => Err(error),
+            };
+        }
+
+        let spinner = spinner.get_or_insert_with(|| {
+            let spinner = if show_progress {
+                ProgressBar::new_spinner()
+            } else {
+                ProgressBar::hidden()
+            };
+            spinner.set_style(ProgressStyle::default_spinner().template("{spinner} {msg}"));
+            spinner.enable_steady_tick(100);
+            spinner
+        });
+        spinner.set_message(&format!(
+            "Waiting ({}s left): {:#}",
+            (deadline - now).as_secs(),
+            error
+        ));
+        log::debug!("Waiting: {:?}", error);
+
+        std::thread::sleep(delay.min(deadline - now));
+        delay = (delay * 2).min(Duration::from_secs(1));
+    }
+}
+
+/// Asks the user which of the connected probes should be used.
+///
+/// The choice can be saved to the local config file of the package, so the question is not
+/// asked again. Only `Embed.local.toml` is edited, for other formats the section to add is printed.
+pub fn pick<'a>(
+    list: &'a [DebugProbeInfo],
+    aliases: &BTreeMap<String, ProbeAlias>,
+    project: &Project,
+) -> Result<&'a DebugProbeInfo> {
+    println!("The following probes were found:");
+    for (num, info) in list.iter().enumerate() {
+        match alias_of(info, aliases) {
+            Some(alias) => println!("  [{}] {} alias {}", num, describe(info), alias),
+            None => println!("  [{}] {}", num, describe(info)),
+        }
+    }
+
+    let info = loop {
+        let answer = prompt(&format!("Select a probe [0-{}]: ", list.len() - 1))?;
+        match answer.parse::<usize>().ok().and_then(|num| list.get(num)) {
+            Some(info) => break info,
+            None => println!("'{}' is not one of the listed probes.", answer),
+        }
+    };
+
+    let path = local_config_path(project)?;
+    // Only TOML files are edited, so the comments and layout of other formats are not lost.
+    if path
+        .extension()
+        .map_or(false, |extension| extension != "toml")
+    {
+        println!(
+            "Add this to {} to use the probe without being asked:\n{}",
+            path.display(),
+            snippet(&path, info)
+        );
+        return Ok(info);
+    }
+    let answer = prompt(&format!("Save this choice to {}? [y/N] ", path.display()))?;
+    if matches!(answer.as_str(), "y" | "Y" | "yes") {
+        save(&path, info)?;
+        logging::println(format!(
+            "       {} {}",
+            "Saved".green().bold(),
+            path.display()
+        ));
+    }
+
+    Ok(info)
+}
+
+/// Describes a probe with its type, VID:PID and serial number.
+pub fn describe(info: &DebugProbeInfo) -> String {
+    let mut description = format!(
+        "{} ({:?}) {:04x}:{:04x}",
+        info.identifier, info.probe_type, info.vendor_id, info.product_id
+    );
+    if let Some(serial) = &info.serial_number {
+        description += &format!(" serial {}", serial);
+    }
+    description
+}
+
+/// Returns the name of the chip a session is attached to, if it can be told from its memory map.
+///
+/// A session does not tell which chip was detected when attaching with `TargetSelector::Auto`,
+/// so the memory map is compared with all known chips. Chips which cannot be told apart
+/// by their memory map give no name.
+pub fn chip_name(session: &Session) -> Option<String> {
+    let families = match families() {
+        Ok(families) => families,
+        Err(error) => {
+            log::info!("Failed to read the known chips: {}", error);
+            return None;
+        }
+    };
+
+    let mut matching = families
+        .iter()
+        .flat_map(|family| family.variants.iter())
+        .filter(|chip| chip.memory_map.as_ref() == session.memory_map())
+        .map(|chip| chip.name.to_string());
+    match (matching.next(), matching.next()) {
+        (Some(name), None) => Some(name),
+        (Some(first), Some(second)) => {
+            log::info!(
+                "The chip could be {}, {} or another one with the same memory map",
+                first,
+                second
+            );
+            None
+        }
+        (None, _) => None,
+    }
+}
+
+/// Returns the name of the alias which selects the given probe, if any.
+pub fn alias_of<'a>(
+    info: &DebugProbeInfo,
+    aliases: &'a BTreeMap<String, ProbeAlias>,
+) -> Option<&'a str> {
+    aliases
+        .iter()
+        .find(|(name, alias)| {
+            Selection::from_alias(name, alias)
+                .map(|selection| selection.matches(info))
+                .unwrap_or(false)
+        })
+        .map(|(name, _)| name.as_str())
+}
+
+fn alias_names(aliases: &BTreeMap<String, ProbeAlias>) -> String {
+    if aliases.is_empty() {
+        "none".to_string()
+    } else {
+        aliases.keys().cloned().collect::<Vec<_>>().join(", ")
+    }
+}
+
+/// Prints `question` and returns the trimmed answer.
+fn prompt(question: &str) -> Result<String> {
+    print!("{}", question);
+    std::io::stdout().flush()?;
+
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer)? == 0 {
+        bail!("No probe was selected.");
+    }
+    Ok(answer.trim().to_string())
+}
+
+/// Returns the path of the local config file in the package directory, or the current directory.
+///
+/// This is the existing `Embed.local` file in any format, or else `Embed.local.toml`.
+fn local_config_path(project: &Project) -> Result<PathBuf> {
+    let dir = match &project.package_dir {
+        Some(dir) => dir.clone(),
+        None => std::env::current_dir()?,
+    };
+    Ok(local_config_in(&dir))
+}
+
+fn local_config_in(dir: &Path) -> PathBuf {
+    config::find_files(&dir.join("Embed.local"))
+        .into_iter()
+        .next()
+        .map(|(path, _)| path)
+        .unwrap_or_else(|| dir.join("Embed.local.toml"))
+}
+
+/// Returns the `default.probe` section for the probe in the JSON or YAML format of `path`.
+fn snippet(path: &Path, info: &DebugProbeInfo) -> String {
+    let mut probe = vec![
+        ("usb_vid", format!("{:04x}", info.vendor_id)),
+        ("usb_pid", format!("{:04x}", info.product_id)),
+    ];
+    if let Some(serial) = &info.serial_number {
+        probe.push(("serial", serial.clone()));
+    }
+
+    if path
+        .extension()
+        .map_or(false, |extension| extension == "json")
+    {
+        let probe: serde_json::Map<_, _> = probe
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.into()))
+            .collect();
+        serde_json::json!({ "default": { "probe": probe } }).to_string()
+    } else {
+        let mut yaml = "default:\n  probe:\n".to_string();
+        for (key, value) in probe {
+            yaml += &format!("    {}: \"{}\"\n", key, value);
+        }
+        yaml
+    }
+}
+
+/// Writes the probe to the `default.probe` section of the given config file,
+/// keeping everything else in it as it is.
+fn save(path: &Path, info: &DebugProbeInfo) -> Result<()> {
+    let contents = if path.exists() {
+        std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?
+    } else {
+        String::new()
+    };
+    let mut document = contents
+        .parse::<Document>()
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+    let mut default = Table::new();
+    default.set_implicit(true);
+    let probe = document
+        .as_table_mut()
+        .entry("default")
+        .or_insert(Item::Table(default))
+        .as_table_like_mut()
+        .and_then(|default| {
+            default
+                .entry("probe")
+                .or_insert_with(table)
+                .as_table_like_mut()
+        })
+        .ok_or_else(|| anyhow!("'default.probe' in {} is not a table", path.display()))?;
+
+    probe.insert("usb_vid", value(format!("{:04x}", info.vendor_id)));
+    probe.insert("usb_pid", value(format!("{:04x}", info.product_id)));
+    match &info.serial_number {
+        Some(serial) => probe.insert("serial", value(serial.as_str())),
+        None => probe.remove("serial"),
+    };
+
+    std::fs::write(path, document.to_string())
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{local_config_in, save, snippet, Selection};
+    use crate::{config::ProbeAlias, test_util::TempDir};
+    use probe_rs::{DebugProbeInfo, DebugProbeType};
+    use std::{collections::BTreeMap, path::Path};
+
+    #[test]
+    fn selects_probes_by_alias() {
+        let mut aliases = BTreeMap::new();
+        aliases.insert(
+            "bench-3-nrf".to_string(),
+            ProbeAlias {
+                usb_vid: "1366".to_string(),
+                usb_pid: "1015".to_string(),
+                serial: Some("000683000000".to_string()),
+            },
+        );
+
+        let selection = Selection::parse("bench-3-nrf", &aliases).unwrap();
+        assert_eq!(
+            selection.to_string(),
+            "bench-3-nrf (1366:1015:000683000000)"
+        );
+
+        let mut info = DebugProbeInfo {
+            identifier: "J-Link".to_string(),
+            vendor_id: 0x1366,
+            product_id: 0x1015,
+            serial_number: Some("000683000000".to_string()),
+            probe_type: DebugProbeType::JLink,
+        };
+        assert!(selection.matches(&info));
+        info.serial_number = Some("000683999999".to_string());
+        assert!(!selection.matches(&info));
+
+        let probe = crate::config::Probe {
+            usb_vid: None,
+            usb_pid: None,
+            serial: None,
+            alias: Some("bench-4-stm".to_string()),
+            protocol: probe_rs::WireProtocol::Swd,
+            speed: None,
+            cache_speed: true,
+            wait_timeout: 0,
+        };
+        let error = Selection::from_config(&probe, &aliases).unwrap_err();
+        assert!(error.to_string().contains("'bench-4-stm'"));
+    }
+
+    #[test]
+    fn saves_probe() {
+        let dir = TempDir::new("saves-probe");
+        let path = dir.write(
+            "Embed.local.toml",
+            "# My probe\n[default.rtt]\nenabled = true\n",
+        );
+
+        let info = DebugProbeInfo {
+            identifier: "J-Link".to_string(),
+            vendor_id: 0x1366,
+            product_id: 0x1015,
+            serial_number: Some("000683000000".to_string()),
+            probe_type: DebugProbeType::JLink,
+        };
+        save(&path, &info).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+
+        assert_eq!(
+            saved,
+            "# My probe\n[default.rtt]\nenabled = true\n\n[default.probe]\n\
+             usb_vid = \"1366\"\nusb_pid = \"1015\"\nserial = \"000683000000\"\n"
+        );
+    }
+
+    #[test]
+    fn uses_the_existing_local_config() {
+        let dir = TempDir::new("uses-the-existing-local-config");
+        assert_eq!(
+            local_config_in(dir.path()),
+            dir.path().join("Embed.local.toml")
+        );
+
+        let yaml = dir.write("Embed.local.yaml", "");
+        assert_eq!(local_config_in(dir.path()), yaml);
+
+        let info = DebugProbeInfo {
+            identifier: "J-Link".to_string(),
+            vendor_id: 0x1366,
+            product_id: 0x1015,
+            serial_number: Some("000683000000".to_string()),
+            probe_type: DebugProbeType::JLink,
+        };
+        assert_eq!(
+            snippet(&yaml, &info),
+            "default:\n  probe:\n    usb_vid: \"1366\"\n    usb_pid: \"1015\"\n    serial: \"000683000000\"\n"
+        );
+        assert_eq!(
+            snippet(Path::new("Embed.local.json"), &info),
+            r#"{"default":{"probe":{"serial":"000683000000","usb_pid":"1015","usb_vid":"1366"}}}"#
+        );
+    }
+}
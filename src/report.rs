@@ -0,0 +1,360 @@
+use anyhow::{Context, Result};
+use probe_rs::flashing::ProgressEvent;
+use serde::Serialize;
+use std::{
+    fs,
+    hash::Hasher,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Instant,
+};
+
+use crate::plan::{Block, Layout, Rates};
+
+/// A report of a flashing run, which can be collected to track the flashing time.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    /// The version of cargo-embed.
+    pub version: String,
+    /// When flashing started, in RFC 3339 format.
+    pub started: String,
+    /// The chip as it was given, or as it was detected if it could be told from its memory map.
+    pub chip: Option<String>,
+    pub probe: ProbeReport,
+    pub speed_khz: u32,
+    pub elf: Option<PathBuf>,
+    /// The FNV-1a hash of the ELF file, in hex.
+    pub elf_hash: Option<String>,
+    /// Whether the flash was erased and programmed, which it is not if it is disabled or up to date.
+    pub flashed: bool,
+    pub verified: bool,
+    /// The time of flashing and verifying in seconds.
+    pub seconds: f64,
+    pub phases: Phases,
+    /// The progress events in the order they happened.
+    pub timeline: Vec<Entry>,
+}
+
+/// The probe which was used for flashing.
+#[derive(Debug, Serialize)]
+pub struct ProbeReport {
+    pub name: String,
+    /// The VID:PID, serial number and alias of the probe.
+    pub selection: String,
+}
+
+impl Report {
+    /// Writes the report as JSON.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write the report to {}", path.display()))
+    }
+}
+
+/// Returns the hash of the file at `path`, in hex.
+pub fn hash_file(path: &Path) -> Result<String> {
+    let mut hasher = Fnv1a::default();
+    hasher.write(&fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?);
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+/// The 64 bit FNV-1a hash, which is stable across builds and platforms.
+#[derive(Debug, Clone, Copy)]
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// A progress event with the time it happened at.
+#[derive(Debug, Clone, Serialize)]
+pub struct Entry {
+    /// The time since flashing started in ms.
+    pub at_ms: f64,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// A progress event of flashing.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A flash region is about to be flashed.
+    Initialized {
+        sectors: usize,
+        pages: usize,
+        fills: usize,
+    },
+    StartedFilling,
+    PageFilled {
+        size: u32,
+        time_ms: u64,
+    },
+    FinishedFilling,
+    FailedFilling,
+    StartedErasing,
+    SectorErased {
+        size: u32,
+        time_ms: u64,
+    },
+    FinishedErasing,
+    FailedErasing,
+    StartedProgramming,
+    PageProgrammed {
+        size: u32,
+        time_ms: u64,
+    },
+    FinishedProgramming,
+    FailedProgramming,
+}
+
+/// The summaries of the phases of flashing.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Phases {
+    pub fill: Phase,
+    pub erase: Phase,
+    pub program: Phase,
+}
+
+/// The summary of one phase of flashing, over all flash regions.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Phase {
+    /// When the phase started first, in ms since flashing started.
+    pub started_ms: Option<f64>,
+    /// When the phase finished last, in ms since flashing started.
+    pub finished_ms: Option<f64>,
+    pub failed: bool,
+    pub bytes: u64,
+    /// The number of filled pages, erased sectors or programmed pages.
+    pub count: usize,
+    pub bytes_per_second: Option<f64>,
+}
+
+impl Phase {
+    fn start(&mut self, at_ms: f64) {
+        self.started_ms.get_or_insert(at_ms);
+    }
+
+    fn add(&mut self, size: u32) {
+        self.bytes += size as u64;
+        self.count += 1;
+    }
+
+    fn finish(&mut self, at_ms: f64, failed: bool) {
+        self.finished_ms = Some(at_ms);
+        self.failed |= failed;
+        if let Some(started_ms) = self.started_ms {
+            let seconds = (at_ms - started_ms) / 1000.0;
+            if !self.failed && self.bytes > 0 && seconds > 0.0 {
+                self.bytes_per_second = Some(self.bytes as f64 / seconds);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    layout: Layout,
+    phases: Phases,
+    timeline: Vec<Entry>,
+}
+
+/// Collects the flash layout, the phases and the timeline while flashing, from the progress events.
+#[derive(Debug)]
+pub struct Recorder {
+    started: Instant,
+    state: Mutex<State>,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            state: Mutex::new(State::default()),
+        }
+    }
+}
+
+impl Recorder {
+    pub fn record(&self, event: &ProgressEvent) {
+        use ProgressEvent::*;
+
+        let at_ms = self.started.elapsed().as_secs_f64() * 1000.0;
+        let mut state = self.state.lock().unwrap();
+        let State {
+            layout,
+            phases,
+            timeline,
+        } = &mut *state;
+
+        let event = match event {
+            // Every flash region is initialized on its own.
+            Initialized { flash_layout } => {
+                let block = |address, size| Block { address, size };
+                layout.extend(Layout {
+                    sectors: flash_layout
+                        .sectors()
+                        .iter()
+                        .map(|sector| block(sector.address(), sector.size()))
+                        .collect(),
+                    pages: flash_layout
+                        .pages()
+                        .iter()
+                        .map(|page| block(page.address(), page.size()))
+                        .collect(),
+                    fills: flash_layout
+                        .fills()
+                        .iter()
+                        .map(|fill| block(fill.address(), fill.size()))
+                        .collect(),
+                });
+                Event::Initialized {
+                    sectors: flash_layout.sectors().len(),
+                    pages: flash_layout.pages().len(),
+                    fills: flash_layout.fills().len(),
+                }
+            }
+            StartedFilling => {
+                phases.fill.start(at_ms);
+                Event::StartedFilling
+            }
+            PageFilled { size, time } => {
+                phases.fill.add(*size);
+                Event::PageFilled {
+                    size: *size,
+                    time_ms: time.as_millis() as u64,
+                }
+            }
+            FinishedFilling => {
+                phases.fill.finish(at_ms, false);
+                Event::FinishedFilling
+            }
+            FailedFilling => {
+                phases.fill.finish(at_ms, true);
+                Event::FailedFilling
+            }
+            StartedErasing => {
+                phases.erase.start(at_ms);
+                Event::StartedErasing
+            }
+            SectorErased { size, time } => {
+                phases.erase.add(*size);
+                Event::SectorErased {
+                    size: *size,
+                    time_ms: time.as_millis() as u64,
+                }
+            }
+            FinishedErasing => {
+                phases.erase.finish(at_ms, false);
+                Event::FinishedErasing
+            }
+            FailedErasing => {
+                phases.erase.finish(at_ms, true);
+                Event::FailedErasing
+            }
+            StartedProgramming => {
+                phases.program.start(at_ms);
+                Event::StartedProgramming
+            }
+            PageProgrammed { size, time } => {
+                phases.program.add(*size);
+                Event::PageProgrammed {
+                    size: *size,
+                    time_ms: time.as_millis() as u64,
+                }
+            }
+            FinishedProgramming => {
+                phases.program.finish(at_ms, false);
+                Event::FinishedProgramming
+            }
+            FailedProgramming => {
+                phases.program.finish(at_ms, true);
+                Event::FailedProgramming
+            }
+        };
+        timeline.push(Entry { at_ms, event });
+    }
+
+    /// Returns the layout of all flash regions.
+    pub fn layout(&self) -> Layout {
+        self.state.lock().unwrap().layout.clone()
+    }
+
+    pub fn phases(&self) -> Phases {
+        self.state.lock().unwrap().phases.clone()
+    }
+
+    pub fn timeline(&self) -> Vec<Entry> {
+        self.state.lock().unwrap().timeline.clone()
+    }
+
+    /// Returns how fast the flash was filled, erased and programmed.
+    pub fn rates(&self) -> Rates {
+        let phases = self.phases();
+        Rates {
+            erase: phases.erase.bytes_per_second,
+            program: phases.program.bytes_per_second,
+            fill: phases.fill.bytes_per_second,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Fnv1a, Recorder};
+    use probe_rs::flashing::ProgressEvent;
+    use std::{hash::Hasher, time::Duration};
+
+    #[test]
+    fn records_phases_and_timeline() {
+        let recorder = Recorder::default();
+        recorder.record(&ProgressEvent::StartedErasing);
+        recorder.record(&ProgressEvent::SectorErased {
+            size: 4096,
+            time: Duration::from_millis(40),
+        });
+        recorder.record(&ProgressEvent::SectorErased {
+            size: 4096,
+            time: Duration::from_millis(41),
+        });
+        std::thread::sleep(Duration::from_millis(2));
+        recorder.record(&ProgressEvent::FinishedErasing);
+        recorder.record(&ProgressEvent::StartedProgramming);
+        recorder.record(&ProgressEvent::FailedProgramming);
+
+        let phases = recorder.phases();
+        assert_eq!((phases.erase.bytes, phases.erase.count), (8192, 2));
+        assert!(phases.erase.bytes_per_second.is_some());
+        assert!(phases.program.failed);
+        assert_eq!(phases.program.bytes_per_second, None);
+        assert_eq!(phases.fill.started_ms, None);
+
+        let timeline = serde_json::to_value(recorder.timeline()).unwrap();
+        assert_eq!(timeline.as_array().unwrap().len(), 6);
+        assert_eq!(timeline[1]["event"], "sector_erased");
+        assert_eq!(timeline[1]["size"], 4096);
+        assert_eq!(timeline[5]["event"], "failed_programming");
+    }
+
+    #[test]
+    fn hashes_with_fnv1a() {
+        let mut hasher = Fnv1a::default();
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63_dc4c_8601_ec8c);
+    }
+}
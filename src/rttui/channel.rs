@@ -3,14 +3,16 @@ use std::fmt;
 use chrono::Local;
 use probe_rs_rtt::{DownChannel, UpChannel};
 
-#[derive(Debug, Copy, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[derive(
+    Debug, Copy, Clone, PartialEq, serde::Serialize, serde::Deserialize, schemars::JsonSchema,
+)]
 pub enum DataFormat {
     String,
     BinaryLE,
     Defmt,
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
 pub struct ChannelConfig {
     pub up: Option<usize>,
     pub down: Option<usize>,
@@ -0,0 +1,311 @@
+use anyhow::{anyhow, bail, Context, Result};
+use colored::*;
+use probe_rs::{
+    config::{MemoryRegion, TargetSelector},
+    Core, MemoryInterface, Probe, Session, WireProtocol,
+};
+use probe_rs_cli_util::logging;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::{
+    cache,
+    config::{Config, Project},
+    probes::Selection,
+};
+
+/// The speeds in kHz which are tried, from slow to fast.
+const SPEEDS: &[u32] = &[100, 400, 1000, 2000, 4000, 8000, 12000, 24000, 50000];
+
+/// How often a block of RAM is written and read back at every speed.
+const ROUNDS: usize = 8;
+
+/// The size of the block of RAM which is written and read back.
+const BLOCK_SIZE: u32 = 4096;
+
+/// The result of testing a single speed.
+#[derive(Debug)]
+pub struct Measurement {
+    /// The speed which was requested.
+    pub requested: u32,
+    /// The speed the probe actually used.
+    pub actual: u32,
+    /// Whether all memory round-trips succeeded at this speed.
+    pub result: Result<()>,
+}
+
+/// Finds the fastest speed at which the target memory can be written and read back reliably.
+///
+/// `open` has to return a probe with the protocol selected.
+/// The speeds in [`SPEEDS`] are tried in order until one fails or the probe cannot go faster.
+/// `report` is called with the result of every tested speed.
+pub fn measure(
+    open: impl Fn() -> Result<Probe>,
+    chip: &TargetSelector,
+    connect_under_reset: bool,
+    mut report: impl FnMut(&Measurement),
+) -> Result<u32> {
+    let mut fastest = None;
+    let mut last_error = None;
+
+    for &requested in SPEEDS {
+        let mut probe = open()?;
+        let actual = match probe.set_speed(requested) {
+            Ok(actual) => actual,
+            Err(error) => {
+                last_error = Some(anyhow::Error::from(error));
+                break;
+            }
+        };
+        // The probe cannot go any faster.
+        if matches!(fastest, Some(fastest) if actual <= fastest) {
+            break;
+        }
+
+        let attached = if connect_under_reset {
+            probe.attach_under_reset(chip.clone())
+        } else {
+            probe.attach(chip.clone())
+        };
+        let result = attached
+            .context("failed attaching to target")
+            .and_then(|mut session| round_trips(&mut session));
+
+        let measurement = Measurement {
+            requested,
+            actual,
+            result,
+        };
+        report(&measurement);
+        match measurement.result {
+            Ok(()) => fastest = Some(actual),
+            Err(error) => {
+                last_error = Some(error);
+                break;
+            }
+        }
+    }
+
+    match (fastest, last_error) {
+        (Some(fastest), _) => Ok(fastest),
+        (None, Some(error)) => Err(error.context("No speed works reliably")),
+        (None, None) => bail!("No speed works reliably"),
+    }
+}
+
+/// Returns the speed to use for `speed = "auto"`, from the cache or by measuring it.
+///
+/// The cache is keyed by the serial number of the probe, so probes without one are measured every time.
+pub fn auto(
+    selection: &Selection,
+    config: &Config,
+    chip: &TargetSelector,
+    chip_name: &str,
+    project: &Project,
+) -> Result<u32> {
+    let serial = Probe::list_all()
+        .into_iter()
+        .find(|info| selection.matches(info))
+        .and_then(|info| info.serial_number);
+    let mut cache = if config.probe.cache_speed {
+        Cache::load(project)
+    } else {
+        None
+    };
+
+    if let (Some(cache), Some(serial)) = (&cache, &serial) {
+        if let Some(speed) = cache.speed(serial, chip_name) {
+            log::info!(
+                "Using the speed of {} kHz measured before, from {}",
+                speed,
+                cache.path().display()
+            );
+            return Ok(speed);
+        }
+    }
+
+    logging::println(format!(
+        "   {} the fastest reliable speed",
+        "Measuring".green().bold()
+    ));
+    let speed = measure(
+        || open(selection, config.probe.protocol),
+        chip,
+        config.general.connect_under_reset,
+        print_measurement,
+    )?;
+
+    if let (Some(cache), Some(serial)) = (&mut cache, &serial) {
+        cache.insert_speed(serial, chip_name, speed);
+        cache.save()?;
+    }
+    Ok(speed)
+}
+
+/// Opens the selected probe and selects the protocol.
+pub fn open(selection: &Selection, protocol: WireProtocol) -> Result<Probe> {
+    let mut probe = selection.open()?;
+    probe
+        .select_protocol(protocol)
+        .context("failed to select protocol")?;
+    Ok(probe)
+}
+
+/// Writes a block at the start of the target RAM with changing patterns and reads it back.
+///
+/// The core is halted during the test. Afterwards the original RAM contents are restored
+/// and the core is resumed if it was running before, also if the test failed.
+fn round_trips(session: &mut Session) -> Result<()> {
+    let range = session
+        .memory_map()
+        .iter()
+        .find_map(|region| match region {
+            MemoryRegion::Ram(ram) => Some(ram.range.clone()),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("The target has no RAM to test the connection with"))?;
+    let address = range.start;
+    let size = BLOCK_SIZE.min(range.end - range.start) as usize;
+
+    let mut core = session.core(0)?;
+    let was_halted = core.core_halted()?;
+    if !was_halted {
+        core.halt(Duration::from_millis(500))?;
+    }
+
+    let mut original = vec![0; size];
+    let result = match core.read_8(address, &mut original) {
+        Ok(()) => {
+            let tested = write_patterns(&mut core, address, size);
+            // A failed restore is reported before a failed test, as it leaves the target changed.
+            core.write_8(address, &original)
+                .with_context(|| format!("Failed to restore the RAM at {:#010x}", address))
+                .and(tested)
+        }
+        Err(error) => Err(error.into()),
+    };
+
+    let resumed = if was_halted {
+        Ok(())
+    } else {
+        core.run().context("Failed to resume the core")
+    };
+    result.and(resumed)
+}
+
+/// Writes `size` bytes at `address` with a different pattern in every round and reads them back.
+fn write_patterns(core: &mut Core, address: u32, size: usize) -> Result<()> {
+    let mut read = vec![0; size];
+    for round in 0..ROUNDS {
+        let written = pattern(round, size);
+        core.write_8(address, &written)?;
+        core.read_8(address, &mut read)?;
+
+        let wrong = written.iter().zip(&read).filter(|(a, b)| a != b).count();
+        if wrong > 0 {
+            bail!(
+                "{} of {} bytes at {:#010x} were read back wrong in round {}",
+                wrong,
+                size,
+                address,
+                round + 1
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Returns `size` bytes which differ in every round and toggle many bits between neighbours.
+fn pattern(round: usize, size: usize) -> Vec<u8> {
+    (0..size)
+        .map(|i| {
+            let varying = (i.wrapping_mul(31) ^ round.wrapping_mul(0x9d)) as u8;
+            match round % 4 {
+                0 => 0x55,
+                1 => 0xaa,
+                2 => varying,
+                _ => !varying,
+            }
+        })
+        .collect()
+}
+
+/// A speed measured for a probe and chip.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CachedSpeed {
+    /// The chip the speed was measured with, as given in the config.
+    chip: String,
+    /// The fastest reliable speed in kHz.
+    speed: u32,
+}
+
+impl cache::Entry for CachedSpeed {
+    const FILE_NAME: &'static str = "probe-speeds.toml";
+}
+
+/// The speeds measured with `speed = "auto"`, by probe serial number.
+pub type Cache = cache::Cache<CachedSpeed>;
+
+impl Cache {
+    /// Returns the speed measured for the probe with the given serial number and chip.
+    pub fn speed(&self, serial: &str, chip: &str) -> Option<u32> {
+        self.get(serial)
+            .filter(|cached| cached.chip == chip)
+            .map(|cached| cached.speed)
+    }
+
+    /// Stores the speed measured for the probe with the given serial number and chip.
+    pub fn insert_speed(&mut self, serial: &str, chip: &str, speed: u32) {
+        self.insert(
+            serial,
+            CachedSpeed {
+                chip: chip.to_string(),
+                speed,
+            },
+        );
+    }
+}
+
+/// Prints the result of testing a single speed.
+pub fn print_measurement(measurement: &Measurement) {
+    let speed = if measurement.actual == measurement.requested {
+        format!("{} kHz", measurement.actual)
+    } else {
+        format!(
+            "{} kHz (requested {} kHz)",
+            measurement.actual, measurement.requested
+        )
+    };
+    match &measurement.result {
+        Ok(()) => logging::println(format!("      {} {}", "Stable".green().bold(), speed)),
+        Err(error) => logging::println(format!(
+            "    {} {}: {:#}",
+            "Unstable".red().bold(),
+            speed,
+            error
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{pattern, Cache};
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn caches_speeds_by_probe_and_chip() {
+        let dir = TempDir::new("caches-speeds");
+        let mut cache = Cache::load_from(dir.path().join("speeds.toml"));
+        assert_eq!(cache.speed("000683000000", "nRF52840_xxAA"), None);
+        cache.insert_speed("000683000000", "nRF52840_xxAA", 8000);
+
+        assert_eq!(cache.speed("000683000000", "nRF52840_xxAA"), Some(8000));
+        assert_eq!(cache.speed("000683000000", "nRF52832_xxAA"), None);
+    }
+
+    #[test]
+    fn changes_pattern_every_round() {
+        assert_ne!(pattern(0, 16), pattern(1, 16));
+        assert_ne!(pattern(2, 16), pattern(3, 16));
+    }
+}
@@ -0,0 +1,35 @@
+use std::path::{Path, PathBuf};
+
+/// An empty directory for the files of a test, which is removed when it is dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates the directory, named after the test so tests running in parallel don't share it.
+    pub fn new(test: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("cargo-embed-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Writes a file into the directory and returns its path.
+    pub fn write(&self, name: &str, contents: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.0.join(name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).unwrap();
+        }
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}